@@ -44,7 +44,9 @@ extern "C" {
 /** Swapping encountered an unrecoverable error */
 #define BOOT_SWAP_TYPE_PANIC    0xff
 
+#ifndef MAX_FLASH_ALIGN
 #define MAX_FLASH_ALIGN         8
+#endif
 extern const uint32_t BOOT_MAX_ALIGN;
 
 struct image_header;
@@ -72,6 +74,9 @@ struct image_trailer {
     uint8_t pad1[MAX_FLASH_ALIGN - 1];
     uint8_t image_ok;
     uint8_t pad2[MAX_FLASH_ALIGN - 1];
+#if MAX_FLASH_ALIGN > 16
+    uint8_t pad3[MAX_FLASH_ALIGN - 16];
+#endif
     uint8_t magic[16];
 };
 
@@ -34,7 +34,7 @@
 #define BOOT_LOG_LEVEL BOOT_LOG_LEVEL_INFO
 #include "bootutil/bootutil_log.h"
 
-int boot_current_slot;
+BOOT_THREAD_LOCAL int boot_current_slot;
 
 const uint32_t boot_img_magic[] = {
     0xf395c277,
@@ -46,6 +46,17 @@ const uint32_t boot_img_magic[] = {
 const uint32_t BOOT_MAGIC_SZ = sizeof boot_img_magic;
 const uint32_t BOOT_MAX_ALIGN = MAX_FLASH_ALIGN;
 
+/*
+ * The magic is written in a single write, so when the flash can be written
+ * in units larger than the magic, the space it takes up is padded at the
+ * front to the largest write size.
+ */
+#if MAX_FLASH_ALIGN > 16
+#define BOOT_MAGIC_AREA_SZ MAX_FLASH_ALIGN
+#else
+#define BOOT_MAGIC_AREA_SZ 16
+#endif
+
 struct boot_swap_table {
     /** * For each field, a value of 0 means "any". */
     uint8_t magic_slot0;
@@ -121,7 +132,7 @@ boot_slots_trailer_sz(uint8_t min_write_sz)
     return /* state for all sectors */
            BOOT_STATUS_MAX_ENTRIES * BOOT_STATUS_STATE_COUNT * min_write_sz +
            BOOT_MAX_ALIGN * 2 /* copy_done + image_ok */                    +
-           BOOT_MAGIC_SZ;
+           BOOT_MAGIC_AREA_SZ;
 }
 
 static uint32_t
@@ -129,13 +140,14 @@ boot_scratch_trailer_sz(uint8_t min_write_sz)
 {
     return BOOT_STATUS_STATE_COUNT * min_write_sz +  /* state for one sector */
            BOOT_MAX_ALIGN                         +  /* image_ok */
-           BOOT_MAGIC_SZ;
+           BOOT_MAGIC_AREA_SZ;
 }
 
 static uint32_t
 boot_magic_off(const struct flash_area *fap)
 {
-    assert(offsetof(struct image_trailer, magic) == 16);
+    assert(offsetof(struct image_trailer, magic) ==
+           MAX_FLASH_ALIGN * 2 + BOOT_MAGIC_AREA_SZ - 16);
     return fap->fa_size - BOOT_MAGIC_SZ;
 }
 
@@ -176,14 +188,14 @@ boot_copy_done_off(const struct flash_area *fap)
 {
     assert(fap->fa_id != FLASH_AREA_IMAGE_SCRATCH);
     assert(offsetof(struct image_trailer, copy_done) == 0);
-    return fap->fa_size - BOOT_MAGIC_SZ - BOOT_MAX_ALIGN * 2;
+    return fap->fa_size - BOOT_MAGIC_AREA_SZ - BOOT_MAX_ALIGN * 2;
 }
 
 static uint32_t
 boot_image_ok_off(const struct flash_area *fap)
 {
-    assert(offsetof(struct image_trailer, image_ok) == 8);
-    return fap->fa_size - BOOT_MAGIC_SZ - BOOT_MAX_ALIGN;
+    assert(offsetof(struct image_trailer, image_ok) == MAX_FLASH_ALIGN);
+    return fap->fa_size - BOOT_MAGIC_AREA_SZ - BOOT_MAX_ALIGN;
 }
 
 int
@@ -248,12 +260,31 @@ boot_read_swap_state_by_id(int flash_area_id, struct boot_swap_state *state)
 int
 boot_write_magic(const struct flash_area *fap)
 {
+    uint8_t buf[BOOT_MAGIC_AREA_SZ];
     uint32_t off;
+    uint32_t pad;
+    uint8_t align;
     int rc;
 
-    off = boot_magic_off(fap);
+    align = flash_area_align(fap);
+    pad = align > BOOT_MAGIC_SZ ? align - BOOT_MAGIC_SZ : 0;
+    assert(pad + BOOT_MAGIC_SZ <= sizeof buf);
+
+    off = boot_magic_off(fap) - pad;
+
+    /* Nothing else is written to the padding, so it still holds the flash's
+     * erased value.  Write back what is there rather than assuming what that
+     * value is.
+     */
+    if (pad > 0) {
+        rc = flash_area_read(fap, off, buf, pad);
+        if (rc != 0) {
+            return BOOT_EFLASH;
+        }
+    }
+    memcpy(buf + pad, boot_img_magic, BOOT_MAGIC_SZ);
 
-    rc = flash_area_write(fap, off, boot_img_magic, BOOT_MAGIC_SZ);
+    rc = flash_area_write(fap, off, buf, pad + BOOT_MAGIC_SZ);
     if (rc != 0) {
         return BOOT_EFLASH;
     }
@@ -40,6 +40,16 @@ struct flash_area;
 
 #define BOOT_TMPBUF_SZ  256
 
+/*
+ * The simulator runs the bootloader from several threads at once, so the
+ * state that a boot keeps in static storage is per thread there.
+ */
+#ifdef __BOOTSIM__
+#define BOOT_THREAD_LOCAL __thread
+#else
+#define BOOT_THREAD_LOCAL
+#endif
+
 /*
  * Maintain state of copy progress.
  */
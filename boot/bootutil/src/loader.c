@@ -41,7 +41,7 @@
 #include "mcuboot_config/mcuboot_config.h"
 #endif
 
-static struct boot_loader_state boot_data;
+static BOOT_THREAD_LOCAL struct boot_loader_state boot_data;
 
 struct boot_status_table {
     /**
@@ -155,8 +155,14 @@ boot_status_source(void)
     rc = boot_read_swap_state_by_id(FLASH_AREA_IMAGE_0, &state_slot0);
     assert(rc == 0);
 
+#ifdef MCUBOOT_OVERWRITE_ONLY
+    /* There may be no scratch area, and nothing is ever written to it. */
+    memset(&state_scratch, 0xff, sizeof state_scratch);
+    state_scratch.magic = BOOT_MAGIC_UNSET;
+#else
     rc = boot_read_swap_state_by_id(FLASH_AREA_IMAGE_SCRATCH, &state_scratch);
     assert(rc == 0);
+#endif
 
     BOOT_LOG_SWAP_STATE("Image 0", &state_slot0);
     BOOT_LOG_SWAP_STATE("Scratch", &state_scratch);
@@ -283,17 +289,22 @@ static uint8_t
 boot_write_sz(void)
 {
     uint8_t elem_sz;
-    uint8_t align;
 
     /* Figure out what size to write update status update as.  The size depends
      * on what the minimum write size is for scratch area, active image slot.
      * We need to use the bigger of those 2 values.
      */
     elem_sz = hal_flash_align(boot_img_fa_device_id(&boot_data, 0));
-    align = hal_flash_align(boot_scratch_fa_device_id(&boot_data));
-    if (align > elem_sz) {
-        elem_sz = align;
+#ifndef MCUBOOT_OVERWRITE_ONLY
+    {
+        uint8_t align;
+
+        align = hal_flash_align(boot_scratch_fa_device_id(&boot_data));
+        if (align > elem_sz) {
+            elem_sz = align;
+        }
     }
+#endif
 
     return elem_sz;
 }
@@ -507,7 +518,7 @@ done:
 static int
 boot_image_check(struct image_header *hdr, const struct flash_area *fap)
 {
-    static uint8_t tmpbuf[BOOT_TMPBUF_SZ];
+    static BOOT_THREAD_LOCAL uint8_t tmpbuf[BOOT_TMPBUF_SZ];
 
     if (bootutil_img_validate(hdr, fap, tmpbuf, BOOT_TMPBUF_SZ,
                               NULL, 0, NULL)) {
@@ -522,7 +533,7 @@ split_image_check(struct image_header *app_hdr,
                   struct image_header *loader_hdr,
                   const struct flash_area *loader_fap)
 {
-    static void *tmpbuf;
+    static BOOT_THREAD_LOCAL void *tmpbuf;
     uint8_t loader_hash[32];
 
     if (!tmpbuf) {
@@ -707,7 +718,7 @@ boot_copy_sector(int flash_area_id_src, int flash_area_id_dst,
     int chunk_sz;
     int rc;
 
-    static uint8_t buf[1024];
+    static BOOT_THREAD_LOCAL uint8_t buf[1024];
 
     fap_src = NULL;
     fap_dst = NULL;
@@ -1212,8 +1223,8 @@ boot_go(struct boot_rsp *rsp)
      * necessary because the gcc option "-fdata-sections" doesn't seem to have
      * any effect in older gcc versions (e.g., 4.8.4).
      */
-    static boot_sector_t slot0_sectors[BOOT_MAX_IMG_SECTORS];
-    static boot_sector_t slot1_sectors[BOOT_MAX_IMG_SECTORS];
+    static BOOT_THREAD_LOCAL boot_sector_t slot0_sectors[BOOT_MAX_IMG_SECTORS];
+    static BOOT_THREAD_LOCAL boot_sector_t slot1_sectors[BOOT_MAX_IMG_SECTORS];
     boot_data.imgs[0].sectors = slot0_sectors;
     boot_data.imgs[1].sectors = slot1_sectors;
 
@@ -1223,9 +1234,11 @@ boot_go(struct boot_rsp *rsp)
         rc = flash_area_open(fa_id, &BOOT_IMG_AREA(&boot_data, slot));
         assert(rc == 0);
     }
+#ifndef MCUBOOT_OVERWRITE_ONLY
     rc = flash_area_open(FLASH_AREA_IMAGE_SCRATCH,
                          &BOOT_SCRATCH_AREA(&boot_data));
     assert(rc == 0);
+#endif
 
     /* Determine the sector layout of the image slots and scratch area. */
     rc = boot_read_sectors();
@@ -1324,7 +1337,9 @@ boot_go(struct boot_rsp *rsp)
     rsp->br_hdr = boot_img_hdr(&boot_data, slot);
 
  out:
+#ifndef MCUBOOT_OVERWRITE_ONLY
     flash_area_close(BOOT_SCRATCH_AREA(&boot_data));
+#endif
     for (slot = 0; slot < BOOT_NUM_SLOTS; slot++) {
         flash_area_close(BOOT_IMG_AREA(&boot_data, BOOT_NUM_SLOTS - 1 - slot));
     }
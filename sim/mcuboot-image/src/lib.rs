@@ -0,0 +1,577 @@
+//! mcuboot image format
+//!
+//! An mcuboot image consists of a header, the image body, and a list of TLV (type-length-value)
+//! entries that carry integrity information about the image.  This crate parses and validates
+//! these images without going through the bootloader's C code.  `Image::parse` follows the same
+//! rules as `bootutil_img_validate`, which skips TLV types it doesn't know.  `Image::parse_strict`
+//! also rejects those, to catch images from tools that emit TLVs the bootloader would ignore.
+
+#[macro_use] extern crate error_chain;
+extern crate ring;
+extern crate untrusted;
+#[cfg(test)] extern crate pem;
+
+use ring::{digest, signature};
+
+error_chain! {
+    errors {
+        BadMagic(magic: u32) {
+            description("Bad image magic")
+            display("Bad image magic: 0x{:08x}", magic)
+        }
+        Truncated(t: String) {
+            description("Image is truncated")
+            display("Image is truncated: {}", t)
+        }
+        TruncatedTlv(offset: usize) {
+            description("TLV runs past the end of the TLV area")
+            display("TLV at 0x{:x} runs past the end of the TLV area", offset)
+        }
+        UnknownTlv(kind: u8, offset: usize) {
+            description("Unknown TLV type")
+            display("Unknown TLV type {} at 0x{:x}", kind, offset)
+        }
+        TlvSize(kind: u8, len: usize) {
+            description("Invalid TLV size")
+            display("Invalid size {} for TLV type {}", len, kind)
+        }
+        MissingTlv(kind: u8) {
+            description("Required TLV is not present")
+            display("TLV type {} is required, but not present", kind)
+        }
+        Flags(t: String) {
+            description("Invalid image flags")
+            display("Invalid image flags: {}", t)
+        }
+        HashMismatch {
+            description("Image hash does not match")
+            display("Image hash does not match")
+        }
+        BadKeyId(key_id: u8) {
+            description("No key for key_id")
+            display("No key for key_id {}", key_id)
+        }
+        BadSignature {
+            description("Image signature is not valid")
+            display("Image signature is not valid")
+        }
+        Unsupported(t: String) {
+            description("Unsupported image")
+            display("Unsupported image: {}", t)
+        }
+    }
+}
+
+pub const IMAGE_MAGIC: u32 = 0x96f3b83c;
+pub const IMAGE_HEADER_SIZE: usize = 32;
+
+// Image header flags.
+pub const IMAGE_F_PIC: u32 = 0x000001;
+pub const IMAGE_F_SHA256: u32 = 0x000002;
+pub const IMAGE_F_PKCS15_RSA2048_SHA256: u32 = 0x000004;
+pub const IMAGE_F_ECDSA224_SHA256: u32 = 0x000008;
+pub const IMAGE_F_NON_BOOTABLE: u32 = 0x000010;
+pub const IMAGE_F_ECDSA256_SHA256: u32 = 0x000020;
+pub const IMAGE_F_PKCS1_PSS_RSA2048_SHA256: u32 = 0x000040;
+
+// Image trailer TLV types.
+pub const IMAGE_TLV_SHA256: u8 = 1;
+pub const IMAGE_TLV_RSA2048: u8 = 2;
+pub const IMAGE_TLV_ECDSA224: u8 = 3;
+pub const IMAGE_TLV_ECDSA256: u8 = 4;
+
+const TLV_HEADER_SIZE: usize = 4;
+
+/// The image version.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u16,
+    pub build_num: u32,
+}
+
+/// The image header.  All fields are little endian in flash.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub magic: u32,
+    pub tlv_size: u16,
+    pub key_id: u8,
+    pub hdr_size: u16,
+    pub img_size: u32,
+    pub flags: u32,
+    pub ver: ImageVersion,
+}
+
+impl ImageHeader {
+    /// Decode a header from the start of the given data.
+    pub fn parse(data: &[u8]) -> Result<ImageHeader> {
+        if data.len() < IMAGE_HEADER_SIZE {
+            bail!(ErrorKind::Truncated(format!("{} bytes is too short for the header",
+                                               data.len())));
+        }
+
+        let header = ImageHeader {
+            magic: get_u32(&data[0..]),
+            tlv_size: get_u16(&data[4..]),
+            key_id: data[6],
+            hdr_size: get_u16(&data[8..]),
+            img_size: get_u32(&data[12..]),
+            flags: get_u32(&data[16..]),
+            ver: ImageVersion {
+                major: data[20],
+                minor: data[21],
+                revision: get_u16(&data[22..]),
+                build_num: get_u32(&data[24..]),
+            },
+        };
+
+        if header.magic != IMAGE_MAGIC {
+            bail!(ErrorKind::BadMagic(header.magic));
+        }
+
+        Ok(header)
+    }
+
+    /// Encode this header as it would be written to flash.  The padding fields are zero.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(IMAGE_HEADER_SIZE);
+        put_u32(&mut result, self.magic);
+        put_u16(&mut result, self.tlv_size);
+        result.push(self.key_id);
+        result.push(0);
+        put_u16(&mut result, self.hdr_size);
+        put_u16(&mut result, 0);
+        put_u32(&mut result, self.img_size);
+        put_u32(&mut result, self.flags);
+        result.push(self.ver.major);
+        result.push(self.ver.minor);
+        put_u16(&mut result, self.ver.revision);
+        put_u32(&mut result, self.ver.build_num);
+        put_u32(&mut result, 0);
+        assert_eq!(result.len(), IMAGE_HEADER_SIZE);
+        result
+    }
+}
+
+/// A single entry from the TLV area.
+#[derive(Debug)]
+pub struct Tlv<'a> {
+    pub kind: u8,
+    /// Offset of the TLV's data from the start of the image.
+    pub offset: usize,
+    pub data: &'a [u8],
+}
+
+/// A public key that an image can be verified against.  These are in the same form as the keys
+/// built into the bootloader.
+#[derive(Clone, Debug)]
+pub enum PublicKey {
+    /// An RSA key, as a DER encoded PKCS#1 RSAPublicKey.
+    Rsa(Vec<u8>),
+    /// An ECDSA P-256 key, as a DER encoded SubjectPublicKeyInfo.
+    EcdsaP256(Vec<u8>),
+}
+
+/// A parsed image, borrowing the data it was parsed from.
+#[derive(Debug)]
+pub struct Image<'a> {
+    pub header: ImageHeader,
+    pub tlvs: Vec<Tlv<'a>>,
+    data: &'a [u8],
+}
+
+impl<'a> Image<'a> {
+    /// Parse the header and TLV area of an image.  Any data past the end of the TLV area, such as
+    /// padding or the image trailer, is ignored.  TLVs of unknown types are kept, without any
+    /// check on their size.
+    pub fn parse(data: &'a [u8]) -> Result<Image<'a>> {
+        Image::parse_tlvs(data, false)
+    }
+
+    /// Parse an image like `parse`, but fail with `UnknownTlv` on any TLV of an unknown type.
+    pub fn parse_strict(data: &'a [u8]) -> Result<Image<'a>> {
+        Image::parse_tlvs(data, true)
+    }
+
+    fn parse_tlvs(data: &'a [u8], strict: bool) -> Result<Image<'a>> {
+        let header = ImageHeader::parse(data)?;
+
+        let hdr_size = header.hdr_size as usize;
+        if hdr_size < IMAGE_HEADER_SIZE {
+            bail!(ErrorKind::Truncated(format!("hdr_size {} is smaller than the header",
+                                               hdr_size)));
+        }
+
+        let tlv_start = hdr_size + header.img_size as usize;
+        let tlv_end = tlv_start + header.tlv_size as usize;
+        if tlv_end > data.len() {
+            bail!(ErrorKind::Truncated(format!("image needs {} bytes, only {} present",
+                                               tlv_end, data.len())));
+        }
+
+        let mut tlvs = vec![];
+        let mut off = tlv_start;
+        while off < tlv_end {
+            if off + TLV_HEADER_SIZE > tlv_end {
+                bail!(ErrorKind::TruncatedTlv(off));
+            }
+            let kind = data[off];
+            let len = get_u16(&data[off + 2..]) as usize;
+            if off + TLV_HEADER_SIZE + len > tlv_end {
+                bail!(ErrorKind::TruncatedTlv(off));
+            }
+
+            // These are the same size limits that the bootloader enforces.
+            let size_ok = match kind {
+                IMAGE_TLV_SHA256 => len == 32,
+                IMAGE_TLV_RSA2048 => len == 256,
+                IMAGE_TLV_ECDSA224 => len >= 64,
+                IMAGE_TLV_ECDSA256 => len >= 72,
+                _ if strict => bail!(ErrorKind::UnknownTlv(kind, off)),
+                _ => true,
+            };
+            if !size_ok {
+                bail!(ErrorKind::TlvSize(kind, len));
+            }
+
+            tlvs.push(Tlv {
+                kind: kind,
+                offset: off + TLV_HEADER_SIZE,
+                data: &data[off + TLV_HEADER_SIZE .. off + TLV_HEADER_SIZE + len],
+            });
+            off += TLV_HEADER_SIZE + len;
+        }
+
+        Ok(Image {
+            header: header,
+            tlvs: tlvs,
+            data: data,
+        })
+    }
+
+    /// The part of the image covered by the hash and signature: the header and the body.
+    pub fn payload(&self) -> &'a [u8] {
+        &self.data[.. self.header.hdr_size as usize + self.header.img_size as usize]
+    }
+
+    /// The image body, without the header.
+    pub fn body(&self) -> &'a [u8] {
+        &self.payload()[self.header.hdr_size as usize ..]
+    }
+
+    /// Find the TLV of the given type that the bootloader uses.  If there is more than one, it is
+    /// the last, as `bootutil_img_validate` replaces each with the next it finds.
+    pub fn find_tlv(&self, kind: u8) -> Option<&Tlv<'a>> {
+        self.tlvs.iter().rev().find(|t| t.kind == kind)
+    }
+
+    /// Check that the SHA256 TLV matches the contents of the image.
+    pub fn verify_hash(&self) -> Result<()> {
+        if self.header.flags & IMAGE_F_SHA256 == 0 {
+            bail!(ErrorKind::Flags("SHA256 flag is not set".to_owned()));
+        }
+
+        let tlv = self.find_tlv(IMAGE_TLV_SHA256)
+            .ok_or_else(|| ErrorKind::MissingTlv(IMAGE_TLV_SHA256))?;
+        let hash = digest::digest(&digest::SHA256, self.payload());
+        if hash.as_ref() != tlv.data {
+            bail!(ErrorKind::HashMismatch);
+        }
+        Ok(())
+    }
+
+    /// Validate the image the way the bootloader would: the hash must match, and if the flags
+    /// call for a signature, it must be present and verify against the key selected by key_id.
+    pub fn validate(&self, keys: &[PublicKey]) -> Result<()> {
+        self.verify_hash()?;
+
+        let flags = self.header.flags;
+        let (kind, alg): (u8, &'static signature::VerificationAlgorithm) =
+            if flags & IMAGE_F_PKCS1_PSS_RSA2048_SHA256 != 0 {
+                (IMAGE_TLV_RSA2048, &signature::RSA_PSS_2048_8192_SHA256 as &signature::VerificationAlgorithm)
+            } else if flags & IMAGE_F_PKCS15_RSA2048_SHA256 != 0 {
+                (IMAGE_TLV_RSA2048, &signature::RSA_PKCS1_2048_8192_SHA256 as &signature::VerificationAlgorithm)
+            } else if flags & IMAGE_F_ECDSA256_SHA256 != 0 {
+                (IMAGE_TLV_ECDSA256, &signature::ECDSA_P256_SHA256_ASN1 as &signature::VerificationAlgorithm)
+            } else if flags & IMAGE_F_ECDSA224_SHA256 != 0 {
+                bail!(ErrorKind::Unsupported("ECDSA P-224 signatures".to_owned()));
+            } else {
+                // Only a hash.
+                return Ok(());
+            };
+
+        let tlv = self.find_tlv(kind).ok_or_else(|| ErrorKind::MissingTlv(kind))?;
+        let key_id = self.header.key_id;
+        let key = keys.get(key_id as usize).ok_or_else(|| ErrorKind::BadKeyId(key_id))?;
+
+        let (key, sig) = match (kind, key) {
+            (IMAGE_TLV_RSA2048, &PublicKey::Rsa(ref der)) => (&der[..], tlv.data),
+            (IMAGE_TLV_ECDSA256, &PublicKey::EcdsaP256(ref der)) => {
+                (ec_point(der)?, der_sequence(tlv.data)?)
+            }
+            _ => bail!(ErrorKind::BadKeyId(key_id)),
+        };
+
+        signature::verify(alg,
+                          untrusted::Input::from(key),
+                          untrusted::Input::from(self.payload()),
+                          untrusted::Input::from(sig))
+            .map_err(|_| ErrorKind::BadSignature)?;
+        Ok(())
+    }
+}
+
+// ring wants the bare EC point, which is the final bit string of the SubjectPublicKeyInfo.
+fn ec_point(spki: &[u8]) -> Result<&[u8]> {
+    const POINT_SIZE: usize = 65;
+    if spki.len() < POINT_SIZE || spki[spki.len() - POINT_SIZE] != 0x04 {
+        bail!(ErrorKind::Unsupported("P-256 key is not an uncompressed point".to_owned()));
+    }
+    Ok(&spki[spki.len() - POINT_SIZE ..])
+}
+
+// ECDSA signatures are padded out to a fixed size.  Return just the DER sequence.
+fn der_sequence(sig: &[u8]) -> Result<&[u8]> {
+    if sig.len() < 2 || sig[0] != 0x30 || sig[1] & 0x80 != 0 ||
+        2 + sig[1] as usize > sig.len()
+    {
+        bail!(ErrorKind::BadSignature);
+    }
+    Ok(&sig[.. 2 + sig[1] as usize])
+}
+
+fn get_u16(data: &[u8]) -> u16 {
+    data[0] as u16 | (data[1] as u16) << 8
+}
+
+fn get_u32(data: &[u8]) -> u32 {
+    get_u16(data) as u32 | (get_u16(&data[2..]) as u32) << 16
+}
+
+fn put_u16(dest: &mut Vec<u8>, value: u16) {
+    dest.push(value as u8);
+    dest.push((value >> 8) as u8);
+}
+
+fn put_u32(dest: &mut Vec<u8>, value: u32) {
+    put_u16(dest, value as u16);
+    put_u16(dest, (value >> 16) as u16);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Image, ImageHeader, ImageVersion, Error, ErrorKind, PublicKey, Result};
+    use super::{IMAGE_MAGIC, IMAGE_F_SHA256, IMAGE_F_PKCS1_PSS_RSA2048_SHA256,
+                IMAGE_F_ECDSA256_SHA256, IMAGE_TLV_SHA256, IMAGE_TLV_RSA2048, IMAGE_TLV_ECDSA256};
+    use pem;
+    use ring::{digest, rand, signature};
+    use ring::signature::{EcdsaKeyPair, RsaKeyPair};
+    use untrusted;
+
+    // Build the header and body of an image, with room for the given TLVs.
+    fn make_payload(body: &[u8], flags: u32, tlv_size: usize) -> Vec<u8> {
+        let header = ImageHeader {
+            magic: IMAGE_MAGIC,
+            tlv_size: tlv_size as u16,
+            key_id: 0,
+            hdr_size: 32,
+            img_size: body.len() as u32,
+            flags: IMAGE_F_SHA256 | flags,
+            ver: ImageVersion {
+                major: 1,
+                minor: 2,
+                revision: 3,
+                build_num: 4,
+            },
+        };
+
+        let mut image = header.to_bytes();
+        image.extend_from_slice(body);
+        image
+    }
+
+    fn push_tlv(image: &mut Vec<u8>, kind: u8, data: &[u8]) {
+        image.extend_from_slice(&[kind, 0, data.len() as u8, (data.len() >> 8) as u8]);
+        image.extend_from_slice(data);
+    }
+
+    // Build a hash-only image with the given body.
+    fn make_image(body: &[u8]) -> Vec<u8> {
+        let mut image = make_payload(body, 0, 4 + 32);
+        let hash = digest::digest(&digest::SHA256, &image);
+        push_tlv(&mut image, IMAGE_TLV_SHA256, hash.as_ref());
+        image
+    }
+
+    // Build an image with a hash and a signature TLV of the given type and size.  The signature
+    // from `sign` is padded out to the size.
+    fn make_signed<F>(body: &[u8], flags: u32, kind: u8, len: usize, sign: F) -> Vec<u8>
+        where F: Fn(&[u8]) -> Vec<u8>
+    {
+        let mut image = make_payload(body, flags, 4 + 32 + 4 + len);
+        let hash = digest::digest(&digest::SHA256, &image);
+        let mut sig = sign(&image);
+        assert!(sig.len() <= len);
+        sig.resize(len, 0);
+        push_tlv(&mut image, IMAGE_TLV_SHA256, hash.as_ref());
+        push_tlv(&mut image, kind, &sig);
+        image
+    }
+
+    fn pem_contents(data: &[u8]) -> Vec<u8> {
+        pem::parse(data).unwrap().contents
+    }
+
+    fn sign_rsa(key: &[u8], payload: &[u8]) -> Vec<u8> {
+        let key = RsaKeyPair::from_der(untrusted::Input::from(&pem_contents(key))).unwrap();
+        let mut sig = vec![0; key.public_modulus_len()];
+        key.sign(&signature::RSA_PSS_SHA256, &rand::SystemRandom::new(), payload, &mut sig)
+            .unwrap();
+        sig
+    }
+
+    fn sign_p256(key: &[u8], payload: &[u8]) -> Vec<u8> {
+        let key = EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+                                           untrusted::Input::from(&pem_contents(key))).unwrap();
+        key.sign(&rand::SystemRandom::new(), untrusted::Input::from(payload)).unwrap()
+            .as_ref().to_vec()
+    }
+
+    #[test]
+    fn test_header() {
+        let image = make_image(&[0x55; 100]);
+        let header = ImageHeader::parse(&image).unwrap();
+        assert_eq!(header.img_size, 100);
+        assert_eq!(header.ver.revision, 3);
+        assert_eq!(header.to_bytes(), &image[..32]);
+    }
+
+    #[test]
+    fn test_valid() {
+        let mut image = make_image(&[0x55; 100]);
+        // Trailing padding is not part of the image.
+        image.extend_from_slice(&[0xff; 16]);
+        let img = Image::parse(&image).unwrap();
+        assert_eq!(img.tlvs.len(), 1);
+        assert_eq!(img.body(), &[0x55; 100][..]);
+        img.validate(&[]).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let good = make_image(&[0x55; 100]);
+
+        let mut image = good.clone();
+        image[0] ^= 1;
+        assert!(parse(&image).is_kind(|k| match *k { ErrorKind::BadMagic(_) => true, _ => false }));
+
+        let image = &good[.. good.len() - 1];
+        assert!(parse(image).is_kind(|k| match *k { ErrorKind::Truncated(_) => true, _ => false }));
+
+        // Claim a longer hash than the TLV area holds.
+        let mut image = good.clone();
+        image[132 + 2] = 33;
+        assert!(parse(&image).is_kind(|k| match *k { ErrorKind::TruncatedTlv(_) => true, _ => false }));
+
+        // Shrink the TLV area to match a short hash.
+        let mut image = good.clone();
+        image[4] = 4 + 31;
+        image[132 + 2] = 31;
+        assert!(parse(&image).is_kind(|k| match *k { ErrorKind::TlvSize(1, 31) => true, _ => false }));
+
+        // An unknown TLV is only an error when parsing strictly.  Without the hash TLV, the image
+        // doesn't validate either way.
+        let mut image = good.clone();
+        image[132] = 0x42;
+        assert!(Image::parse_strict(&image)
+                .is_kind(|k| match *k { ErrorKind::UnknownTlv(0x42, 132) => true, _ => false }));
+        assert_eq!(Image::parse(&image).unwrap().tlvs[0].kind, 0x42);
+        assert!(parse(&image).is_kind(|k| match *k { ErrorKind::MissingTlv(1) => true, _ => false }));
+
+        let mut image = good.clone();
+        image[40] ^= 1;
+        assert!(parse(&image).is_kind(|k| match *k { ErrorKind::HashMismatch => true, _ => false }));
+    }
+
+    #[test]
+    fn test_duplicate_tlv() {
+        // The bootloader uses the last hash, so only a good one there makes the image valid.
+        let good = make_image(&[0x55; 100]);
+        let hash = good[good.len() - 32 ..].to_vec();
+        let mut bad_hash = hash.clone();
+        bad_hash[0] ^= 1;
+
+        let mut image = make_payload(&[0x55; 100], 0, 2 * (4 + 32));
+        push_tlv(&mut image, IMAGE_TLV_SHA256, &bad_hash);
+        push_tlv(&mut image, IMAGE_TLV_SHA256, &hash);
+        assert!(parse(&image).is_ok());
+
+        let mut image = make_payload(&[0x55; 100], 0, 2 * (4 + 32));
+        push_tlv(&mut image, IMAGE_TLV_SHA256, &hash);
+        push_tlv(&mut image, IMAGE_TLV_SHA256, &bad_hash);
+        assert!(parse(&image).is_kind(|k| match *k { ErrorKind::HashMismatch => true, _ => false }));
+    }
+
+    #[test]
+    fn test_rsa() {
+        let private = include_bytes!("../../../root-rsa-2048.pem");
+        let keys = [PublicKey::Rsa(
+            pem_contents(include_bytes!("../../keys/root-rsa-2048-pub.pem")))];
+        let other = [PublicKey::Rsa(
+            pem_contents(include_bytes!("../../keys/second-rsa-2048-pub.pem")))];
+
+        let image = make_signed(&[0x55; 100], IMAGE_F_PKCS1_PSS_RSA2048_SHA256, IMAGE_TLV_RSA2048,
+                                256, |payload| sign_rsa(private, payload));
+        Image::parse(&image).unwrap().validate(&keys).unwrap();
+        check_bad_signature(&image, &keys, &other);
+    }
+
+    #[test]
+    fn test_p256() {
+        let private = include_bytes!("../../../root-ec-p256-pkcs8.pem");
+        let keys = [PublicKey::EcdsaP256(
+            pem_contents(include_bytes!("../../keys/root-ec-p256-pub.pem")))];
+        let other = [PublicKey::EcdsaP256(
+            pem_contents(include_bytes!("../../keys/second-ec-p256-pub.pem")))];
+
+        let image = make_signed(&[0x55; 100], IMAGE_F_ECDSA256_SHA256, IMAGE_TLV_ECDSA256, 72,
+                                |payload| sign_p256(private, payload));
+        Image::parse(&image).unwrap().validate(&keys).unwrap();
+        check_bad_signature(&image, &keys, &other);
+    }
+
+    // A signed image fails with a different key, without any key for its key_id, and with a
+    // damaged signature.
+    fn check_bad_signature(image: &[u8], keys: &[PublicKey], other: &[PublicKey]) {
+        let bad_sig = |k: &ErrorKind| match *k { ErrorKind::BadSignature => true, _ => false };
+
+        assert!(Image::parse(image).unwrap().validate(other).is_kind(&bad_sig));
+        assert!(Image::parse(image).unwrap().validate(&[])
+                .is_kind(|k| match *k { ErrorKind::BadKeyId(0) => true, _ => false }));
+
+        // Flip a bit in the signature, past the DER header of an ECDSA one.
+        let sig_off = Image::parse(image).unwrap().tlvs[1].offset;
+        let mut image = image.to_vec();
+        image[sig_off + 8] ^= 1;
+        assert!(Image::parse(&image).unwrap().validate(keys).is_kind(&bad_sig));
+    }
+
+    fn parse(data: &[u8]) -> Result<()> {
+        Image::parse(data)?.validate(&[])
+    }
+
+    // Helper checks for the result type.
+    trait EChecker {
+        fn is_kind<F: Fn(&ErrorKind) -> bool>(&self, check: F) -> bool;
+    }
+
+    impl<T> EChecker for Result<T> {
+        fn is_kind<F: Fn(&ErrorKind) -> bool>(&self, check: F) -> bool {
+            match *self {
+                Err(Error(ref kind, _)) => check(kind),
+                _ => false,
+            }
+        }
+    }
+}
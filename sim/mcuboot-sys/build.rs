@@ -1,46 +1,89 @@
 // Build mcuboot as a library, based on the requested features.
 
 extern crate gcc;
+extern crate pem;
 
 use std::env;
-use std::fs;
-use std::io;
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 
 fn main() {
     // Feature flags.
     let sig_rsa = env::var("CARGO_FEATURE_SIG_RSA").is_ok();
+    let sig_rsa_pkcs1_15 = env::var("CARGO_FEATURE_SIG_RSA_PKCS1_15").is_ok();
     let sig_ecdsa = env::var("CARGO_FEATURE_SIG_ECDSA").is_ok();
+    let sig_ecdsa_p224 = env::var("CARGO_FEATURE_SIG_ECDSA_P224").is_ok();
     let overwrite_only = env::var("CARGO_FEATURE_OVERWRITE_ONLY").is_ok();
+    let large_align = env::var("CARGO_FEATURE_LARGE_ALIGN").is_ok();
 
     let mut conf = gcc::Config::new();
     conf.define("__BOOTSIM__", None);
     conf.define("MCUBOOT_USE_FLASH_AREA_GET_SECTORS", None);
     conf.define("MCUBOOT_VALIDATE_SLOT0", None);
 
+    // Allow the trailer to be written on devices with up to 32 byte write units.  This makes the
+    // trailer larger, so it isn't the default.
+    if large_align {
+        conf.define("MAX_FLASH_ALIGN", Some("32"));
+    }
+
     // Currently, mbed TLS cannot build with both RSA and ECDSA.
     if sig_rsa && sig_ecdsa {
         panic!("mcuboot does not support RSA and ECDSA at the same time");
     }
 
+    // Likewise, the bootloader only verifies a single signature type.
+    if sig_ecdsa_p224 && (sig_rsa || sig_ecdsa) {
+        panic!("mcuboot only supports one signature type at a time");
+    }
+
     if sig_rsa {
         conf.define("MCUBOOT_SIGN_RSA", None);
         conf.define("MCUBOOT_USE_MBED_TLS", None);
+        if sig_rsa_pkcs1_15 {
+            conf.define("MCUBOOT_RSA_PKCS1_15", None);
+        }
 
         conf.define("MCUBOOT_USE_MBED_TLS", None);
         conf.define("MBEDTLS_CONFIG_FILE", Some("<config-boot.h>"));
         conf.include("mbedtls/include");
         conf.file("mbedtls/library/sha256.c");
-        conf.file("../../boot/zephyr/keys.c");
 
         conf.file("mbedtls/library/rsa.c");
         conf.file("mbedtls/library/bignum.c");
         conf.file("mbedtls/library/asn1parse.c");
     } else if sig_ecdsa {
-        conf.define("MCUBOOT_SIGN_ECDSA", None);
+        conf.define("MCUBOOT_SIGN_EC256", None);
         conf.define("MCUBOOT_USE_TINYCRYPT", None);
-        // TODO: Compile files + tinycrypt.
-        panic!("ECDSA not yet implemented in sim");
+
+        // mbed TLS is still needed to parse the ASN.1 of the key and signature.
+        conf.define("MBEDTLS_CONFIG_FILE", Some("<config-asn1.h>"));
+        conf.include("mbedtls/include");
+        conf.include("tinycrypt/lib/include");
+
+        conf.file("tinycrypt/lib/source/utils.c");
+        conf.file("tinycrypt/lib/source/sha256.c");
+        conf.file("tinycrypt/lib/source/ecc.c");
+        conf.file("tinycrypt/lib/source/ecc_dsa.c");
+
+        conf.file("mbedtls/library/asn1parse.c");
+    } else if sig_ecdsa_p224 {
+        conf.define("MCUBOOT_SIGN_EC", None);
+        conf.define("MCUBOOT_USE_MBED_TLS", None);
+
+        // P-224 is verified entirely with mbed TLS.
+        conf.define("CONFIG_BOOT_VERIFY_ECDSA_SIGNATURE", None);
+        conf.define("MBEDTLS_CONFIG_FILE", Some("<config-boot.h>"));
+        conf.include("mbedtls/include");
+        conf.file("mbedtls/library/sha256.c");
+
+        conf.file("mbedtls/library/ecdsa.c");
+        conf.file("mbedtls/library/ecp.c");
+        conf.file("mbedtls/library/ecp_curves.c");
+        conf.file("mbedtls/library/bignum.c");
+        conf.file("mbedtls/library/asn1parse.c");
+        conf.file("mbedtls/library/asn1write.c");
     } else {
         // Neither signature type, only verify sha256.
         conf.define("MCUBOOT_USE_MBED_TLS", None);
@@ -55,11 +98,38 @@ fn main() {
 
     conf.file("../../boot/bootutil/src/image_validate.c");
     conf.file("../../boot/bootutil/src/image_rsa.c");
+    conf.file("../../boot/bootutil/src/image_ec.c");
+    conf.file("../../boot/bootutil/src/image_ec256.c");
     conf.file("../../boot/bootutil/src/loader.c");
     conf.file("../../boot/bootutil/src/caps.c");
     conf.file("../../boot/bootutil/src/bootutil_misc.c");
     conf.file("csupport/run.c");
+
+    // The bootloader trusts the keys listed in BOOTSIM_KEYS, separated by ':', or the sample keys
+    // for the signature type if that isn't set.  Their position in the list is their key_id.
+    println!("cargo:rerun-if-env-changed=BOOTSIM_KEYS");
+    let keys: Vec<PathBuf> = match env::var("BOOTSIM_KEYS") {
+        Ok(keys) => keys.split(':').map(PathBuf::from).collect(),
+        Err(_) => {
+            let names: &[&str] = if sig_rsa {
+                &["root-rsa-2048-pub.pem", "second-rsa-2048-pub.pem"]
+            } else if sig_ecdsa {
+                &["root-ec-p256-pub.pem", "second-ec-p256-pub.pem"]
+            } else if sig_ecdsa_p224 {
+                &["root-ec-p224-pub.pem", "second-ec-p224-pub.pem"]
+            } else {
+                &[]
+            };
+            names.iter().map(|n| Path::new("../keys").join(n)).collect()
+        }
+    };
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let keys_c = out_dir.join("keys.c");
+    gen_keys(&keys, &keys_c).unwrap();
+    conf.file(&keys_c);
     conf.include("../../boot/bootutil/include");
+    // Provides assert.h, which must be found before the system one.
+    conf.include("csupport");
     conf.include("../../boot/zephyr/include");
     conf.debug(true);
     conf.flag("-Wall");
@@ -71,6 +141,58 @@ fn main() {
     walk_dir("csupport").unwrap();
     walk_dir("mbedtls/include").unwrap();
     walk_dir("mbedtls/library").unwrap();
+    if sig_ecdsa {
+        walk_dir("tinycrypt/lib").unwrap();
+    }
+}
+
+// Generate the C table of public keys trusted by the bootloader.  Each key is either PEM or raw DER
+// encoded, and is given to the bootloader as DER.  RSA keys must be in the PKCS#1 RSAPublicKey
+// form, and EC keys in the SubjectPublicKeyInfo form.
+fn gen_keys(keys: &[PathBuf], dest: &Path) -> io::Result<()> {
+    let mut out = File::create(dest)?;
+    writeln!(out, "/* Generated by mcuboot-sys/build.rs, do not edit. */")?;
+    writeln!(out, "")?;
+    writeln!(out, "#include <bootutil/sign_key.h>")?;
+
+    for (id, path) in keys.iter().enumerate() {
+        println!("cargo:rerun-if-changed={}", path.display());
+
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        if data.starts_with(b"-----BEGIN") {
+            let block = pem::parse(data).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               format!("{}: {:?}", path.display(), e))
+            })?;
+            data = block.contents;
+        }
+
+        writeln!(out, "")?;
+        writeln!(out, "/* {} */", path.display())?;
+        writeln!(out, "static const unsigned char key{}[] = {{", id)?;
+        for line in data.chunks(8) {
+            let bytes: Vec<String> = line.iter().map(|b| format!("0x{:02x},", b)).collect();
+            writeln!(out, "    {}", bytes.join(" "))?;
+        }
+        writeln!(out, "}};")?;
+        writeln!(out, "static const unsigned int key{}_len = {};", id, data.len())?;
+    }
+
+    writeln!(out, "")?;
+    if keys.is_empty() {
+        // Without signatures, nothing looks at the table, but the sim still asks for its size.
+        writeln!(out, "const struct bootutil_key bootutil_keys[1];")?;
+    } else {
+        writeln!(out, "const struct bootutil_key bootutil_keys[] = {{")?;
+        for id in 0 .. keys.len() {
+            writeln!(out, "    {{ .key = key{}, .len = &key{}_len }},", id, id)?;
+        }
+        writeln!(out, "}};")?;
+    }
+    writeln!(out, "const int bootutil_key_cnt = {};", keys.len())?;
+
+    Ok(())
 }
 
 // Output the names of all files within a directory so that Cargo knows when to rebuild.
@@ -0,0 +1,16 @@
+/*
+ * Replaces the system assert.h when building for the simulator.  A failed
+ * assertion in the bootloader ends the current boot, rather than the whole
+ * simulation, so that the simulator can check what happens when flash
+ * operations fail.
+ */
+
+#ifndef H_SIM_ASSERT_
+#define H_SIM_ASSERT_
+
+void sim_assert(int x, const char *assertion, const char *file,
+                unsigned int line, const char *function);
+
+#define assert(x) sim_assert((x), #x, __FILE__, __LINE__, __func__)
+
+#endif
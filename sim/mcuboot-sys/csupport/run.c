@@ -13,19 +13,37 @@
 #define BOOT_LOG_LEVEL BOOT_LOG_LEVEL_ERROR
 #include <bootutil/bootutil_log.h>
 
-extern int sim_flash_erase(uint32_t offset, uint32_t size);
-extern int sim_flash_read(uint32_t offset, uint8_t *dest, uint32_t size);
-extern int sim_flash_write(uint32_t offset, const uint8_t *src, uint32_t size);
+extern int sim_flash_erase(uint8_t flash_id, uint32_t offset, uint32_t size);
+extern int sim_flash_read(uint8_t flash_id, uint32_t offset, uint8_t *dest,
+                          uint32_t size);
+extern int sim_flash_write(uint8_t flash_id, uint32_t offset,
+                           const uint8_t *src, uint32_t size);
+extern int sim_flash_interrupted_erase(uint8_t flash_id, uint32_t offset,
+                                       uint32_t size);
+extern int sim_flash_interrupted_write(uint8_t flash_id, uint32_t offset,
+                                       const uint8_t *src, uint32_t size);
 
-static jmp_buf boot_jmpbuf;
-int flash_counter;
+/*
+ * Boots are run from several threads at once, so each thread has its own
+ * flash counter and its own state for the boot it is running.
+ */
+static __thread int flash_counter;
 
-int jumped = 0;
+/* Let the simulator check layouts against the bootloader's limits. */
+const uint32_t sim_max_img_sectors = BOOT_MAX_IMG_SECTORS;
 
-uint8_t sim_flash_align = 1;
-uint8_t flash_area_align(const struct flash_area *area)
+static __thread jmp_buf boot_jmpbuf;
+__thread int jumped = 0;
+static __thread int asserted = 0;
+
+int sim_get_flash_counter(void)
+{
+    return flash_counter;
+}
+
+void sim_set_flash_counter(int counter)
 {
-    return sim_flash_align;
+    flash_counter = counter;
 }
 
 struct area {
@@ -33,6 +51,7 @@ struct area {
     struct flash_area *areas;
     uint32_t num_areas;
     uint8_t id;
+    uint8_t align;
 };
 
 struct area_desc {
@@ -40,19 +59,16 @@ struct area_desc {
     uint32_t num_slots;
 };
 
-static struct area_desc *flash_areas;
+static __thread struct area_desc *flash_areas;
 
-void *(*mbedtls_calloc)(size_t n, size_t size);
-void (*mbedtls_free)(void *ptr);
+void *(*mbedtls_calloc)(size_t n, size_t size) = calloc;
+void (*mbedtls_free)(void *ptr) = free;
 
 int invoke_boot_go(struct area_desc *adesc)
 {
     int res;
     struct boot_rsp rsp;
 
-    mbedtls_calloc = calloc;
-    mbedtls_free = free;
-
     flash_areas = adesc;
     if (setjmp(boot_jmpbuf) == 0) {
         res = boot_go(&rsp);
@@ -61,16 +77,31 @@ int invoke_boot_go(struct area_desc *adesc)
         return res;
     } else {
         flash_areas = NULL;
+        if (asserted) {
+            asserted = 0;
+            return -0x2468a;
+        }
         return -0x13579;
     }
 }
 
+void sim_assert(int x, const char *assertion, const char *file,
+                unsigned int line, const char *function)
+{
+    if (!x) {
+        BOOT_LOG_ERR("%s:%u: %s: Assertion `%s' failed",
+                     file, line, function, assertion);
+        asserted = 1;
+        longjmp(boot_jmpbuf, 1);
+    }
+}
+
 int hal_flash_read(uint8_t flash_id, uint32_t address, void *dst,
                    uint32_t num_bytes)
 {
     // printf("hal_flash_read: %d, 0x%08x (0x%x)\n",
     //        flash_id, address, num_bytes);
-    return sim_flash_read(address, dst, num_bytes);
+    return sim_flash_read(flash_id, address, dst, num_bytes);
 }
 
 int hal_flash_write(uint8_t flash_id, uint32_t address,
@@ -80,9 +111,10 @@ int hal_flash_write(uint8_t flash_id, uint32_t address,
     // fflush(stdout);
     if (--flash_counter == 0) {
         jumped++;
+        sim_flash_interrupted_write(flash_id, address, src, num_bytes);
         longjmp(boot_jmpbuf, 1);
     }
-    return sim_flash_write(address, src, num_bytes);
+    return sim_flash_write(flash_id, address, src, num_bytes);
 }
 
 int hal_flash_erase(uint8_t flash_id, uint32_t address,
@@ -92,14 +124,38 @@ int hal_flash_erase(uint8_t flash_id, uint32_t address,
     // fflush(stdout);
     if (--flash_counter == 0) {
         jumped++;
+        sim_flash_interrupted_erase(flash_id, address, num_bytes);
         longjmp(boot_jmpbuf, 1);
     }
-    return sim_flash_erase(address, num_bytes);
+    return sim_flash_erase(flash_id, address, num_bytes);
 }
 
 uint8_t hal_flash_align(uint8_t flash_id)
 {
-    return sim_flash_align;
+    int i;
+
+    /* Every area on a device has the device's alignment. */
+    for (i = 0; i < flash_areas->num_slots; i++) {
+        if (flash_areas->slots[i].num_areas > 0 &&
+            flash_areas->slots[i].whole.fa_device_id == flash_id)
+            return flash_areas->slots[i].align;
+    }
+
+    printf("Unsupported flash device\n");
+    abort();
+}
+
+uint8_t flash_area_align(const struct flash_area *area)
+{
+    int i;
+
+    for (i = 0; i < flash_areas->num_slots; i++) {
+        if (flash_areas->slots[i].id == area->fa_id)
+            return flash_areas->slots[i].align;
+    }
+
+    printf("Unsupported area\n");
+    abort();
 }
 
 void *os_malloc(size_t size)
@@ -143,7 +199,7 @@ int flash_area_read(const struct flash_area *area, uint32_t off, void *dst,
 {
     BOOT_LOG_DBG("%s: area=%d, off=%x, len=%x",
                  __func__, area->fa_id, off, len);
-    return hal_flash_read(area->fa_id,
+    return hal_flash_read(area->fa_device_id,
                           area->fa_off + off,
                           dst, len);
 }
@@ -153,7 +209,7 @@ int flash_area_write(const struct flash_area *area, uint32_t off, const void *sr
 {
     BOOT_LOG_DBG("%s: area=%d, off=%x, len=%x", __func__,
                  area->fa_id, off, len);
-    return hal_flash_write(area->fa_id,
+    return hal_flash_write(area->fa_device_id,
                            area->fa_off + off,
                            src, len);
 }
@@ -162,7 +218,7 @@ int flash_area_erase(const struct flash_area *area, uint32_t off, uint32_t len)
 {
     BOOT_LOG_DBG("%s: area=%d, off=%x, len=%x", __func__,
                  area->fa_id, off, len);
-    return hal_flash_erase(area->fa_id,
+    return hal_flash_erase(area->fa_device_id,
                            area->fa_off + off,
                            len);
 }
@@ -1,29 +1,40 @@
 //! HAL api for MyNewt applications
 
-use simflash::{Result, Flash};
+use simflash::{ErrorKind, Result, Flash};
 use libc;
 use log::LogLevel;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::mem;
 use std::slice;
 
-// The current active flash device.  The 'static is a lie, and we manage the lifetime ourselves.
-static mut FLASH: Option<*mut Flash> = None;
+// The active flash devices, indexed by device id.  The bootloader calls back on the thread that
+// invoked it, so each thread has its own set.  The 'static is a lie, and we manage the lifetime
+// ourselves.
+thread_local! {
+    static FLASH: RefCell<HashMap<u8, *mut Flash>> = RefCell::new(HashMap::new());
+}
 
-// Set the flash device to be used by the simulation.  The pointer is unsafely stashed away.
-pub unsafe fn set_flash(dev: &mut Flash) {
+// Add a flash device to be used by the simulation.  The pointer is unsafely stashed away.
+pub unsafe fn set_flash(dev_id: u8, dev: &mut Flash) {
     let dev: &'static mut Flash = mem::transmute(dev);
-    FLASH = Some(dev as *mut Flash);
+    FLASH.with(|devs| devs.borrow_mut().insert(dev_id, dev as *mut Flash));
 }
 
 pub unsafe fn clear_flash() {
-    FLASH = None;
+    FLASH.with(|devs| devs.borrow_mut().clear());
+}
+
+fn find_flash(dev_id: u8) -> Option<*mut Flash> {
+    FLASH.with(|devs| devs.borrow().get(&dev_id).cloned())
 }
 
-// Retrieve the flash, returning an error from the enclosing function.  We can't panic here because
-// we've called through C and unwinding is prohibited (it seems to just exit the program).
+// Retrieve the flash device with the given id, returning an error from the enclosing function.  We
+// can't panic here because we've called through C and unwinding is prohibited (it seems to just
+// exit the program).
 macro_rules! get_flash {
-    () => {
-        match FLASH {
+    ($id:expr) => {
+        match find_flash($id) {
             Some(x) => &mut *x,
             None => return -19,
         }
@@ -33,31 +44,55 @@ macro_rules! get_flash {
 // This isn't meant to call directly, but by a wrapper.
 
 #[no_mangle]
-pub extern fn sim_flash_erase(offset: u32, size: u32) -> libc::c_int {
-    let dev = unsafe { get_flash!() };
+pub extern fn sim_flash_erase(dev_id: u8, offset: u32, size: u32) -> libc::c_int {
+    let dev = unsafe { get_flash!(dev_id) };
     map_err(dev.erase(offset as usize, size as usize))
 }
 
 #[no_mangle]
-pub extern fn sim_flash_read(offset: u32, dest: *mut u8, size: u32) -> libc::c_int {
-    let dev = unsafe { get_flash!() };
+pub extern fn sim_flash_read(dev_id: u8, offset: u32, dest: *mut u8, size: u32) -> libc::c_int {
+    let dev = unsafe { get_flash!(dev_id) };
     let mut buf: &mut[u8] = unsafe { slice::from_raw_parts_mut(dest, size as usize) };
     map_err(dev.read(offset as usize, &mut buf))
 }
 
 #[no_mangle]
-pub extern fn sim_flash_write(offset: u32, src: *const u8, size: u32) -> libc::c_int {
-    let dev = unsafe { get_flash!() };
+pub extern fn sim_flash_write(dev_id: u8, offset: u32, src: *const u8, size: u32) -> libc::c_int {
+    let dev = unsafe { get_flash!(dev_id) };
     let buf: &[u8] = unsafe { slice::from_raw_parts(src, size as usize) };
     map_err(dev.write(offset as usize, &buf))
 }
 
+// Called instead of the above when power is lost during the operation.
+
+#[no_mangle]
+pub extern fn sim_flash_interrupted_erase(dev_id: u8, offset: u32, size: u32) -> libc::c_int {
+    let dev = unsafe { get_flash!(dev_id) };
+    map_err(dev.interrupted_erase(offset as usize, size as usize))
+}
+
+#[no_mangle]
+pub extern fn sim_flash_interrupted_write(dev_id: u8, offset: u32, src: *const u8,
+                                          size: u32) -> libc::c_int {
+    let dev = unsafe { get_flash!(dev_id) };
+    let buf: &[u8] = unsafe { slice::from_raw_parts(src, size as usize) };
+    map_err(dev.interrupted_write(offset as usize, &buf))
+}
+
+// Flash errors are returned to the bootloader as negative errno values, so that a failure can be
+// told apart when debugging the C code.
 fn map_err(err: Result<()>) -> libc::c_int {
     match err {
         Ok(()) => 0,
         Err(e) => {
             warn!("{}", e);
-            -1
+            match *e.kind() {
+                ErrorKind::OutOfBounds(_) => -libc::ERANGE,
+                ErrorKind::Misaligned(_) => -libc::EINVAL,
+                ErrorKind::NotErased(_) => -libc::EIO,
+                ErrorKind::Injected(_) => -libc::EIO,
+                _ => -1,
+            }
         },
     }
 }
@@ -1,6 +1,11 @@
 //! Describe flash areas.
 
-use simflash::{Flash, SimFlash, Sector};
+use c;
+use simflash::{self, ErrorKind, Flash, SimFlashMap, Sector};
+use std::cmp;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
 use std::ptr;
 
 /// Structure to build up the boot area table.
@@ -8,84 +13,94 @@ use std::ptr;
 pub struct AreaDesc {
     areas: Vec<Vec<FlashArea>>,
     whole: Vec<FlashArea>,
-    sectors: Vec<Sector>,
+    sectors: HashMap<u8, Vec<Sector>>,
+    aligns: HashMap<u8, u8>,
 }
 
 impl AreaDesc {
-    pub fn new(flash: &SimFlash) -> AreaDesc {
+    pub fn new(flashmap: &SimFlashMap) -> AreaDesc {
         AreaDesc {
             areas: vec![],
             whole: vec![],
-            sectors: flash.sector_iter().collect(),
+            sectors: flashmap.iter().map(|(&id, flash)| (id, flash.sector_iter().collect()))
+                .collect(),
+            aligns: flashmap.iter().map(|(&id, flash)| (id, flash.align() as u8)).collect(),
         }
     }
 
-    /// Add a slot to the image.  The slot must align with erasable units in the flash device.
-    /// Panics if the description is not valid.  There are also bootloader assumptions that the
-    /// slots are SLOT0, SLOT1, and SCRATCH in that order.
-    pub fn add_image(&mut self, base: usize, len: usize, id: FlashId) {
-        let nid = id as usize;
-        let orig_base = base;
-        let orig_len = len;
-        let mut base = base;
-        let mut len = len;
-
-        while nid > self.areas.len() {
-            self.areas.push(vec![]);
-            self.whole.push(Default::default());
+    /// Add a slot to the image, on the flash device with the given id.  The slot must align with
+    /// erasable units in the flash device.  Panics if the description is not valid.  There are
+    /// also bootloader assumptions that the slots are SLOT0, SLOT1, and SCRATCH in that order.
+    pub fn add_image(&mut self, base: usize, len: usize, id: FlashId, dev_id: u8) {
+        if let Err(e) = self.try_add_image(base, len, id, dev_id) {
+            panic!("{}", e);
         }
+    }
 
-        if nid != self.areas.len() {
-            panic!("Flash areas not added in order");
-        }
+    /// Add a slot to the image, as with `add_image`, but return an error, rather than panicking,
+    /// if the slot doesn't fit the device.
+    pub fn try_add_image(&mut self, base: usize, len: usize, id: FlashId,
+                         dev_id: u8) -> Result<(), LayoutError> {
+        let sectors = match self.sectors.get(&dev_id) {
+            Some(sectors) => sectors,
+            None => return Err(LayoutError::NoDevice { id: id, dev_id: dev_id }),
+        };
 
         let mut area = vec![];
+        let mut off = base;
+        let mut left = len;
 
-        for sector in &self.sectors {
-            if len == 0 {
+        for sector in sectors {
+            if left == 0 {
                 break;
             };
-            if base > sector.base + sector.size - 1 {
+            if off > sector.base + sector.size - 1 {
                 continue;
             }
-            if sector.base != base {
-                panic!("Image does not start on a sector boundary");
+            if sector.base != off || sector.size > left {
+                return Err(LayoutError::Misaligned { id: id, base: base, len: len });
             }
 
             area.push(FlashArea {
                 flash_id: id,
-                device_id: 42,
+                device_id: dev_id,
                 pad16: 0,
                 off: sector.base as u32,
                 size: sector.size as u32,
             });
 
-            base += sector.size;
-            len -= sector.size;
+            off += sector.size;
+            left -= sector.size;
         }
 
-        if len != 0 {
-            panic!("Image goes past end of device");
+        if left != 0 {
+            return Err(LayoutError::PastEnd { id: id, base: base, len: len });
         }
 
+        self.skip_to(id)?;
         self.areas.push(area);
         self.whole.push(FlashArea {
             flash_id: id,
-            device_id: 42,
+            device_id: dev_id,
             pad16: 0,
-            off: orig_base as u32,
-            size: orig_len as u32,
+            off: base as u32,
+            size: len as u32,
         });
+        Ok(())
     }
 
     // Add a simple slot to the image.  This ignores the device layout, and just adds the area as a
     // single unit.  It assumes that the image lines up with image boundaries.  This tests
     // configurations where the partition table uses larger sectors than the underlying flash
     // device.
-    pub fn add_simple_image(&mut self, base: usize, len: usize, id: FlashId) {
+    pub fn add_simple_image(&mut self, base: usize, len: usize, id: FlashId, dev_id: u8) {
+        if let Err(e) = self.skip_to(id) {
+            panic!("{}", e);
+        }
+
         let area = vec![FlashArea {
             flash_id: id,
-            device_id: 42,
+            device_id: dev_id,
             pad16: 0,
             off: base as u32,
             size: len as u32,
@@ -94,22 +109,217 @@ impl AreaDesc {
         self.areas.push(area);
         self.whole.push(FlashArea {
             flash_id: id,
-            device_id: 42,
+            device_id: dev_id,
             pad16: 0,
             off: base as u32,
             size: len as u32,
         });
     }
 
-    // Look for the image with the given ID, and return its base and size.  Panics if the area is
-    // not present.
-    pub fn find(&self, id: FlashId) -> (usize, usize) {
+    // Leave empty slots for any areas before the given ID that have not been added.  The area
+    // with this ID, or a later one, must not have been added already.
+    fn skip_to(&mut self, id: FlashId) -> Result<(), LayoutError> {
+        let nid = id as usize;
+
+        if nid < self.areas.len() {
+            return Err(LayoutError::OutOfOrder(id));
+        }
+
+        while nid > self.areas.len() {
+            self.areas.push(vec![]);
+            self.whole.push(Default::default());
+        }
+        Ok(())
+    }
+
+    // Look for the image with the given ID, and return its base, size, and the device it is on.
+    // Panics if the area is not present.
+    pub fn find(&self, id: FlashId) -> (usize, usize, u8) {
+        match self.try_find(id) {
+            Some(area) => area,
+            None => panic!("Requesting area that is not present in flash"),
+        }
+    }
+
+    /// Look for the image with the given ID, as with `find`, returning None if it is not present.
+    pub fn try_find(&self, id: FlashId) -> Option<(usize, usize, u8)> {
+        self.whole.iter()
+            .find(|area| area.flash_id == id && area.size > 0)
+            .map(|area| (area.off as usize, area.size as usize, area.device_id))
+    }
+
+    /// The write alignment of the area with the given ID, which is that of the device it is on.
+    pub fn align(&self, id: FlashId) -> u8 {
+        let (_, _, dev_id) = self.find(id);
+        self.aligns[&dev_id]
+    }
+
+    /// Return the ID of the area containing the given offset on a device, if there is one.
+    pub fn area_at(&self, dev_id: u8, offset: usize) -> Option<FlashId> {
         for area in &self.whole {
-            if area.flash_id == id {
-                return (area.off as usize, area.size as usize);
+            let base = area.off as usize;
+            if area.device_id == dev_id && offset >= base && offset < base + area.size as usize {
+                return Some(area.flash_id);
+            }
+        }
+        None
+    }
+
+    /// Check that the bootloader can upgrade images with this layout: that both slots and the
+    /// scratch area are present, that the slots have the same sectors, and not too many of them,
+    /// that the scratch area can hold any of those sectors, and that the trailer fits.  When built
+    /// for overwrite only upgrades, there need not be a scratch area.  Returns every problem
+    /// found, rather than just the first.
+    pub fn validate(&self) -> Result<(), Vec<LayoutError>> {
+        let mut errors = vec![];
+
+        for (i, a) in self.whole.iter().enumerate() {
+            for b in self.whole.iter().skip(i + 1) {
+                if a.size > 0 && b.size > 0 && a.device_id == b.device_id &&
+                    a.off < b.off + b.size && b.off < a.off + a.size
+                {
+                    errors.push(LayoutError::Overlap(a.flash_id, b.flash_id));
+                }
+            }
+        }
+
+        // Overwrite only upgrades copy slot 1 straight to slot 0, without a scratch area.
+        let needed: &[FlashId] = if cfg!(feature = "overwrite-only") {
+            &[FlashId::Image0, FlashId::Image1]
+        } else {
+            &[FlashId::Image0, FlashId::Image1, FlashId::ImageScratch]
+        };
+        let missing: Vec<LayoutError> = needed.iter()
+            .filter(|&&id| self.sectors_of(id).is_none())
+            .map(|&id| LayoutError::Missing(id))
+            .collect();
+        if !missing.is_empty() {
+            errors.extend(missing);
+            return Err(errors);
+        }
+
+        // The trailer is only laid out for writes of up to BOOT_MAX_ALIGN bytes.
+        for &id in needed {
+            if self.align(id) as usize > c::boot_max_align() {
+                errors.push(LayoutError::AlignTooLarge {
+                    id: id,
+                    align: self.align(id),
+                    max: c::boot_max_align(),
+                });
+            }
+        }
+
+        let slot0 = self.sectors_of(FlashId::Image0).unwrap();
+        let slot1 = self.sectors_of(FlashId::Image1).unwrap();
+
+        let slot0_sizes: Vec<usize> = slot0.iter().map(|a| a.size as usize).collect();
+        let slot1_sizes: Vec<usize> = slot1.iter().map(|a| a.size as usize).collect();
+        if slot0_sizes != slot1_sizes {
+            errors.push(LayoutError::SlotsIncompatible {
+                slot0: slot0_sizes.clone(),
+                slot1: slot1_sizes,
+            });
+        }
+
+        for &(id, count) in &[(FlashId::Image0, slot0.len()), (FlashId::Image1, slot1.len())] {
+            if count > c::boot_max_img_sectors() {
+                errors.push(LayoutError::TooManySectors {
+                    id: id,
+                    count: count,
+                    max: c::boot_max_img_sectors(),
+                });
             }
         }
-        panic!("Requesting area that is not present in flash");
+
+        for &id in &[FlashId::Image0, FlashId::Image1] {
+            let trailer = c::boot_trailer_sz(self.align(id)) as usize;
+            let (_, size, _) = self.find(id);
+            if trailer >= size {
+                errors.push(LayoutError::NoRoomForImage {
+                    id: id,
+                    align: self.align(id),
+                    trailer: trailer,
+                    size: size,
+                });
+            }
+        }
+
+        if needed.contains(&FlashId::ImageScratch) {
+            let scratch = self.sectors_of(FlashId::ImageScratch).unwrap();
+            let scratch_size: usize = scratch.iter().map(|a| a.size as usize).sum();
+            let largest = slot0_sizes.iter().cloned().max().unwrap_or(0);
+            if scratch_size < largest {
+                errors.push(LayoutError::ScratchTooSmall {
+                    scratch: scratch_size,
+                    sector: largest,
+                });
+            }
+
+            let align = self.align(FlashId::Image0);
+            let scratch_align = self.align(FlashId::ImageScratch);
+            if align != scratch_align {
+                errors.push(LayoutError::AlignMismatch { slot0: align, scratch: scratch_align });
+            }
+
+            // The last sectors of slot 0 are swapped together, as many as fit in scratch, and the
+            // trailer is kept in scratch meanwhile, so it must fit in those sectors.
+            let write_sz = cmp::max(align, scratch_align);
+            let trailer = c::boot_trailer_sz(write_sz) as usize;
+            let mut swapped = 0;
+            for &size in slot0_sizes.iter().rev() {
+                if swapped + size > scratch_size {
+                    break;
+                }
+                swapped += size;
+            }
+            if swapped > 0 && trailer > swapped {
+                errors.push(LayoutError::TrailerTooBig {
+                    align: write_sz,
+                    trailer: trailer,
+                    swapped: swapped,
+                });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    // The sectors of the area with the given ID, if it has been added.
+    fn sectors_of(&self, id: FlashId) -> Option<&[FlashArea]> {
+        match self.areas.get(id as usize) {
+            Some(area) if !area.is_empty() => Some(area),
+            _ => None,
+        }
+    }
+
+    /// Write an image, such as one produced by `imgtool.py sign`, to the start of the area with
+    /// the given ID.  The area must already be erased.  The image is padded with the flash's erased
+    /// value to the largest flash alignment (`BOOT_MAX_ALIGN`), and must leave room for the
+    /// trailer at the area's alignment.
+    pub fn install_image(&self, flashmap: &mut SimFlashMap, id: FlashId,
+                         image: &[u8]) -> simflash::Result<()> {
+        let (base, size, dev_id) = self.find(id);
+        let flash = match flashmap.get_mut(&dev_id) {
+            Some(flash) => flash,
+            None => panic!("No flash device with id {}", dev_id),
+        };
+
+        let mut buf = image.to_vec();
+        while buf.len() % c::boot_max_align() != 0 {
+            buf.push(flash.erased_val());
+        }
+
+        if buf.len() + c::boot_trailer_sz(self.align(id)) as usize > size {
+            let msg = format!("image of {} bytes does not fit in {:?} ({} bytes)",
+                              image.len(), id, size);
+            return Err(ErrorKind::OutOfBounds(msg).into());
+        }
+
+        flash.write(base, &buf)
     }
 
     pub fn get_c(&self) -> CAreaDesc {
@@ -123,6 +333,7 @@ impl AreaDesc {
                 areas.slots[i].whole = self.whole[i].clone();
                 areas.slots[i].num_areas = area.len() as u32;
                 areas.slots[i].id = area[0].flash_id;
+                areas.slots[i].align = self.aligns[&self.whole[i].device_id];
             }
         }
 
@@ -147,6 +358,7 @@ pub struct CArea {
     areas: *const FlashArea,
     num_areas: u32,
     id: FlashId,
+    align: u8,
 }
 
 impl Default for CArea {
@@ -156,6 +368,7 @@ impl Default for CArea {
             whole: Default::default(),
             id: FlashId::BootLoader,
             num_areas: 0,
+            align: 1,
         }
     }
 }
@@ -174,6 +387,127 @@ pub enum FlashId {
     RebootLog = 6
 }
 
+impl FlashId {
+    /// Look up an area by the name used for its partition in the Zephyr device tree, such as
+    /// "image-0".
+    pub fn from_name(name: &str) -> Option<FlashId> {
+        match name {
+            "boot" | "bootloader" | "mcuboot" => Some(FlashId::BootLoader),
+            "image-0" => Some(FlashId::Image0),
+            "image-1" => Some(FlashId::Image1),
+            "image-scratch" => Some(FlashId::ImageScratch),
+            "nffs" => Some(FlashId::Nffs),
+            "core" => Some(FlashId::Core),
+            "reboot-log" => Some(FlashId::RebootLog),
+            _ => None,
+        }
+    }
+}
+
+/// A problem with a flash layout, which would stop the bootloader from using it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LayoutError {
+    /// The area is on a flash device that isn't being simulated.
+    NoDevice { id: FlashId, dev_id: u8 },
+    /// The area doesn't start and end on sector boundaries.
+    Misaligned { id: FlashId, base: usize, len: usize },
+    /// The area goes past the end of its flash device.
+    PastEnd { id: FlashId, base: usize, len: usize },
+    /// The area was added after a later one, or twice.
+    OutOfOrder(FlashId),
+    /// The two areas share some of the same flash.
+    Overlap(FlashId, FlashId),
+    /// An area the bootloader needs isn't present.
+    Missing(FlashId),
+    /// The slots are made up of different sectors, given by their sizes.
+    SlotsIncompatible { slot0: Vec<usize>, slot1: Vec<usize> },
+    /// The area's write alignment is larger than the bootloader was built for (`BOOT_MAX_ALIGN`).
+    AlignTooLarge { id: FlashId, align: u8, max: usize },
+    /// The slot has more sectors than the bootloader's `BOOT_MAX_IMG_SECTORS`.
+    TooManySectors { id: FlashId, count: usize, max: usize },
+    /// The scratch area is smaller than the largest sector of the slots.
+    ScratchTooSmall { scratch: usize, sector: usize },
+    /// The scratch area has a different write alignment than slot 0.  The swap status is
+    /// written to both with the larger one, but read from each with its own.
+    AlignMismatch { slot0: u8, scratch: u8 },
+    /// The trailer fills the whole slot.
+    NoRoomForImage { id: FlashId, align: u8, trailer: usize, size: usize },
+    /// The trailer is larger than the last sectors of slot 0 that are swapped together.
+    TrailerTooBig { align: u8, trailer: usize, swapped: usize },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LayoutError::NoDevice { id, dev_id } =>
+                write!(f, "{:?} is on flash device {}, which doesn't exist", id, dev_id),
+            LayoutError::Misaligned { id, base, len } =>
+                write!(f, "{:?} (0x{:x}, 0x{:x} bytes) does not start and end on sector \
+                           boundaries", id, base, len),
+            LayoutError::PastEnd { id, base, len } =>
+                write!(f, "{:?} (0x{:x}, 0x{:x} bytes) goes past the end of the device",
+                       id, base, len),
+            LayoutError::OutOfOrder(id) =>
+                write!(f, "{:?} was added out of order, or more than once", id),
+            LayoutError::Overlap(a, b) =>
+                write!(f, "{:?} and {:?} overlap", a, b),
+            LayoutError::Missing(id) =>
+                write!(f, "there is no {:?} area", id),
+            LayoutError::SlotsIncompatible { ref slot0, ref slot1 } =>
+                write!(f, "the slots have different sectors, so they can't be swapped: \
+                           slot 0 has {}, slot 1 has {}", describe(slot0), describe(slot1)),
+            LayoutError::AlignTooLarge { id, align, max } =>
+                write!(f, "{:?} has a write alignment of {}, but the bootloader was built for at \
+                           most {}", id, align, max),
+            LayoutError::TooManySectors { id, count, max } =>
+                write!(f, "{:?} has {} sectors, but the bootloader handles at most {}",
+                       id, count, max),
+            LayoutError::ScratchTooSmall { scratch, sector } =>
+                write!(f, "the scratch area (0x{:x} bytes) is smaller than the largest sector \
+                           (0x{:x} bytes)", scratch, sector),
+            LayoutError::AlignMismatch { slot0, scratch } =>
+                write!(f, "the scratch area has a write alignment of {}, but slot 0 has {}",
+                       scratch, slot0),
+            LayoutError::NoRoomForImage { id, align, trailer, size } =>
+                write!(f, "the trailer at alignment {} (0x{:x} bytes) leaves no room for an \
+                           image in {:?} (0x{:x} bytes)", align, trailer, id, size),
+            LayoutError::TrailerTooBig { align, trailer, swapped } =>
+                write!(f, "the trailer at alignment {} (0x{:x} bytes) is larger than the last \
+                           sectors swapped through scratch (0x{:x} bytes)",
+                       align, trailer, swapped),
+        }
+    }
+}
+
+impl error::Error for LayoutError {
+    fn description(&self) -> &str {
+        "flash layout can't be used by the bootloader"
+    }
+}
+
+/// Describe a list of sector sizes, such as "4 x 0x4000, 0x10000".
+fn describe(sizes: &[usize]) -> String {
+    let mut runs: Vec<(usize, usize)> = vec![];
+    for &size in sizes {
+        if let Some(last) = runs.last_mut() {
+            if last.0 == size {
+                last.1 += 1;
+                continue;
+            }
+        }
+        runs.push((size, 1));
+    }
+
+    let runs: Vec<String> = runs.iter().map(|&(size, count)| {
+        if count == 1 {
+            format!("0x{:x}", size)
+        } else {
+            format!("{} x 0x{:x}", count, size)
+        }
+    }).collect();
+    runs.join(", ")
+}
+
 impl Default for FlashId {
     fn default() -> FlashId {
         FlashId::BootLoader
@@ -190,3 +524,151 @@ pub struct FlashArea {
     size: u32,
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::{AreaDesc, FlashId, LayoutError};
+    use c;
+    use simflash::{SimFlash, SimFlashMap};
+
+    fn flashmap(sectors: Vec<usize>, align: usize) -> SimFlashMap {
+        let mut flashmap = SimFlashMap::new();
+        flashmap.insert(0, SimFlash::new(sectors, align));
+        flashmap
+    }
+
+    // The sectors of an stm32f4.
+    fn stm_sectors() -> Vec<usize> {
+        vec![16 * 1024, 16 * 1024, 16 * 1024, 16 * 1024, 64 * 1024,
+             128 * 1024, 128 * 1024, 128 * 1024]
+    }
+
+    fn k64f(align: usize) -> AreaDesc {
+        let mut areadesc = AreaDesc::new(&flashmap(vec![4096; 128], align));
+        areadesc.add_image(0x020000, 0x020000, FlashId::Image0, 0);
+        areadesc.add_image(0x040000, 0x020000, FlashId::Image1, 0);
+        areadesc.add_image(0x060000, 0x001000, FlashId::ImageScratch, 0);
+        areadesc
+    }
+
+    #[test]
+    fn test_valid() {
+        assert_eq!(k64f(1).validate(), Ok(()));
+        assert_eq!(k64f(8).validate(), Ok(()));
+
+        let mut areadesc = AreaDesc::new(&flashmap(stm_sectors(), 1));
+        areadesc.add_image(0x020000, 0x020000, FlashId::Image0, 0);
+        areadesc.add_image(0x040000, 0x020000, FlashId::Image1, 0);
+        areadesc.add_image(0x060000, 0x020000, FlashId::ImageScratch, 0);
+        assert_eq!(areadesc.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_large_align() {
+        let max = c::boot_max_align();
+        if max < 32 {
+            let errors = k64f(max * 2).validate().unwrap_err();
+            assert_eq!(errors[0], LayoutError::AlignTooLarge {
+                id: FlashId::Image0,
+                align: (max * 2) as u8,
+                max: max,
+            });
+        }
+    }
+
+    #[test]
+    fn test_add() {
+        let mut areadesc = AreaDesc::new(&flashmap(vec![4096; 128], 1));
+        assert_eq!(areadesc.try_add_image(0x1000, 0x1800, FlashId::Image0, 0),
+                   Err(LayoutError::Misaligned { id: FlashId::Image0, base: 0x1000, len: 0x1800 }));
+        assert_eq!(areadesc.try_add_image(0x7f000, 0x2000, FlashId::Image0, 0),
+                   Err(LayoutError::PastEnd { id: FlashId::Image0, base: 0x7f000, len: 0x2000 }));
+        assert_eq!(areadesc.try_add_image(0, 0x1000, FlashId::Image0, 1),
+                   Err(LayoutError::NoDevice { id: FlashId::Image0, dev_id: 1 }));
+        assert_eq!(areadesc.try_add_image(0, 0x1000, FlashId::Image1, 0), Ok(()));
+        assert_eq!(areadesc.try_add_image(0x1000, 0x1000, FlashId::Image0, 0),
+                   Err(LayoutError::OutOfOrder(FlashId::Image0)));
+    }
+
+    #[test]
+    fn test_invalid() {
+        // Slots with different sectors, and a scratch area smaller than the large sectors.
+        let mut areadesc = AreaDesc::new(&flashmap(stm_sectors(), 1));
+        areadesc.add_image(0x000000, 0x020000, FlashId::Image0, 0);
+        areadesc.add_image(0x020000, 0x020000, FlashId::Image1, 0);
+        areadesc.add_image(0x040000, 0x020000, FlashId::ImageScratch, 0);
+        let errors = areadesc.validate().unwrap_err();
+        assert_eq!(errors[0], LayoutError::SlotsIncompatible {
+            slot0: vec![0x4000, 0x4000, 0x4000, 0x4000, 0x10000],
+            slot1: vec![0x20000],
+        });
+        assert_eq!(errors[0].to_string(),
+                   "the slots have different sectors, so they can't be swapped: \
+                    slot 0 has 4 x 0x4000, 0x10000, slot 1 has 0x20000");
+
+        // More sectors than the bootloader has room for.
+        let mut areadesc = AreaDesc::new(&flashmap(vec![4096; 512], 1));
+        areadesc.add_image(0x000000, 0x080000, FlashId::Image0, 0);
+        areadesc.add_image(0x080000, 0x080000, FlashId::Image1, 0);
+        areadesc.add_image(0x100000, 0x001000, FlashId::ImageScratch, 0);
+        let errors = areadesc.validate().unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|e| match *e {
+            LayoutError::TooManySectors { count: 128, .. } => true,
+            _ => false,
+        }));
+
+        // Overlapping areas.
+        let mut areadesc = AreaDesc::new(&flashmap(vec![4096; 128], 1));
+        areadesc.add_image(0x020000, 0x020000, FlashId::Image0, 0);
+        areadesc.add_image(0x030000, 0x020000, FlashId::Image1, 0);
+        areadesc.add_image(0x060000, 0x001000, FlashId::ImageScratch, 0);
+        assert_eq!(areadesc.validate(),
+                   Err(vec![LayoutError::Overlap(FlashId::Image0, FlashId::Image1)]));
+    }
+
+    #[test]
+    fn test_no_scratch() {
+        let mut areadesc = AreaDesc::new(&flashmap(vec![4096; 128], 1));
+        areadesc.add_image(0x020000, 0x020000, FlashId::Image0, 0);
+        areadesc.add_image(0x040000, 0x020000, FlashId::Image1, 0);
+        if cfg!(feature = "overwrite-only") {
+            assert_eq!(areadesc.validate(), Ok(()));
+        } else {
+            assert_eq!(areadesc.validate(), Err(vec![LayoutError::Missing(FlashId::ImageScratch)]));
+        }
+    }
+
+    // Overwrite only upgrades don't use the scratch area, so don't check it.
+    #[cfg(not(feature = "overwrite-only"))]
+    #[test]
+    fn test_invalid_scratch() {
+        let mut areadesc = AreaDesc::new(&flashmap(stm_sectors(), 1));
+        areadesc.add_image(0x020000, 0x020000, FlashId::Image0, 0);
+        areadesc.add_image(0x040000, 0x020000, FlashId::Image1, 0);
+        areadesc.add_image(0x010000, 0x010000, FlashId::ImageScratch, 0);
+        assert_eq!(areadesc.validate(),
+                   Err(vec![LayoutError::ScratchTooSmall { scratch: 0x10000, sector: 0x20000 }]));
+
+        // At large alignments, the trailer doesn't fit in a single small sector.
+        if c::boot_max_align() >= 16 {
+            match k64f(16).validate() {
+                Err(ref errors) if errors.len() == 1 => match errors[0] {
+                    LayoutError::TrailerTooBig { align: 16, swapped: 0x1000, .. } => (),
+                    ref e => panic!("Unexpected error {:?}", e),
+                },
+                other => panic!("Unexpected result {:?}", other),
+            }
+        }
+
+        // The scratch area on a device with a different alignment.
+        let mut flashmap = flashmap(vec![4096; 128], 4);
+        flashmap.insert(1, SimFlash::new(vec![4096; 16], 1));
+        let mut areadesc = AreaDesc::new(&flashmap);
+        areadesc.add_image(0x020000, 0x020000, FlashId::Image0, 0);
+        areadesc.add_image(0x040000, 0x020000, FlashId::Image1, 0);
+        areadesc.add_image(0x000000, 0x001000, FlashId::ImageScratch, 1);
+        assert_eq!(areadesc.validate(),
+                   Err(vec![LayoutError::AlignMismatch { slot0: 4, scratch: 1 }]));
+    }
+}
@@ -0,0 +1,610 @@
+//! Import flash areas from board definitions.
+//!
+//! Boards already describe their partitions, in the `partitions` nodes of a Zephyr device tree,
+//! or in the `bsp.flash_map` section of a Mynewt `bsp.yml`.  These build an `AreaDesc` from those
+//! descriptions, so that the simulator can test the partitions a board actually uses.  The
+//! geometry of the flash itself comes from the simulated flash devices.
+
+use area::{AreaDesc, FlashId};
+use serde_yaml::{self, Value};
+use simflash::SimFlashMap;
+use std::io;
+
+impl AreaDesc {
+    /// Build the areas from the `partitions` nodes of a Zephyr device tree source.  The
+    /// partitions are matched to areas by their `label`, and those the bootloader doesn't use,
+    /// such as "storage", are ignored.  `flashes` names the flash node of each flash device, in
+    /// order of device id, either by the node's label, as in `&flash0`, or by its name.  A
+    /// `partitions` node is on the device of the flash node it is in.  The source should already
+    /// have been through the C preprocessor, if it needs it.
+    pub fn from_dts(flashmap: &SimFlashMap, text: &str, flashes: &[&str]) -> io::Result<AreaDesc> {
+        let tokens = tokenize(text)?;
+        let mut pos = 0;
+        let root = parse_node(String::new(), None, &tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return Err(invalid("unbalanced '}' in device tree"));
+        }
+
+        let mut tables = vec![];
+        root.find("partitions", &mut tables);
+
+        let mut parts = vec![];
+        for &(flash, table) in &tables {
+            let dev_id = flashes.iter().position(|&name| flash.is_named(name));
+
+            for node in &table.children {
+                if node.base_name() != "partition" {
+                    continue;
+                }
+
+                let label = match node.string("label") {
+                    Some(label) => label,
+                    None => return Err(invalid(format!("partition {} has no label",
+                                                       node.name))),
+                };
+                let id = match FlashId::from_name(label) {
+                    Some(id) => id,
+                    None => {
+                        info!("Ignoring partition {} ({})", node.name, label);
+                        continue;
+                    }
+                };
+
+                let reg = match node.cells("reg") {
+                    Some(cells) => cells,
+                    None => return Err(invalid(format!("partition {} has no reg", label))),
+                };
+                let dev_id = match dev_id {
+                    Some(dev_id) if dev_id <= 255 => dev_id,
+                    _ => return Err(invalid(format!("partition {} is in {}, which is not one \
+                                                     of the flash devices", label,
+                                                    flash.name))),
+                };
+                if reg.len() != 2 {
+                    return Err(invalid(format!("partition {}: expecting a reg of one address \
+                                                and one size cell", label)));
+                }
+
+                parts.push(Part {
+                    name: label.to_string(),
+                    id: id,
+                    dev_id: dev_id as u8,
+                    base: parse_int(&reg[0])?,
+                    len: parse_int(&reg[1])?,
+                });
+            }
+        }
+
+        AreaDesc::from_parts(flashmap, parts)
+    }
+
+    /// Build the areas from the `bsp.flash_map` section of a Mynewt `bsp.yml`.  The areas are
+    /// matched by name, such as `FLASH_AREA_IMAGE_0`, and user areas the bootloader doesn't use
+    /// are ignored.
+    pub fn from_bsp_yml(flashmap: &SimFlashMap, text: &str) -> io::Result<AreaDesc> {
+        let bsp: Value = serde_yaml::from_str(text).map_err(|e| invalid(e.to_string()))?;
+
+        let areas = match bsp["bsp.flash_map"]["areas"].as_mapping() {
+            Some(areas) => areas,
+            None => return Err(invalid("no bsp.flash_map areas in bsp.yml")),
+        };
+
+        let mut parts = vec![];
+        for (name, area) in areas.iter() {
+            let name = match name.as_str() {
+                Some(name) => name,
+                None => return Err(invalid(format!("bad flash area name {:?}", name))),
+            };
+            let id = if name.starts_with("FLASH_AREA_") {
+                FlashId::from_name(&name["FLASH_AREA_".len()..].to_lowercase().replace('_', "-"))
+            } else {
+                None
+            };
+            let id = match id {
+                Some(id) => id,
+                None => {
+                    info!("Ignoring flash area {}", name);
+                    continue;
+                }
+            };
+
+            let field = |key: &str| -> io::Result<usize> {
+                match area[key] {
+                    Value::Number(ref n) => match n.as_u64() {
+                        Some(n) => Ok(n as usize),
+                        None => Err(invalid(format!("{}: bad {} {}", name, key, n))),
+                    },
+                    Value::String(ref s) => parse_int(s),
+                    Value::Null => Err(invalid(format!("{} has no {}", name, key))),
+                    ref v => Err(invalid(format!("{}: bad {} {:?}", name, key, v))),
+                }
+            };
+
+            let dev_id = field("device")?;
+            if dev_id > 255 {
+                return Err(invalid(format!("{}: bad device {}", name, dev_id)));
+            }
+
+            parts.push(Part {
+                name: name.to_string(),
+                id: id,
+                dev_id: dev_id as u8,
+                base: field("offset")?,
+                len: field("size")?,
+            });
+        }
+
+        AreaDesc::from_parts(flashmap, parts)
+    }
+
+    // Add the parts in the order the bootloader needs them.
+    fn from_parts(flashmap: &SimFlashMap, mut parts: Vec<Part>) -> io::Result<AreaDesc> {
+        parts.sort_by_key(|p| p.id as u8);
+
+        for pair in parts.windows(2) {
+            if pair[0].id == pair[1].id {
+                return Err(invalid(format!("{} and {} are both {:?}",
+                                           pair[0].name, pair[1].name, pair[0].id)));
+            }
+        }
+
+        let mut areadesc = AreaDesc::new(flashmap);
+        for part in parts {
+            if let Err(e) = areadesc.try_add_image(part.base, part.len, part.id, part.dev_id) {
+                return Err(invalid(format!("{}: {}", part.name, e)));
+            }
+        }
+        Ok(areadesc)
+    }
+}
+
+/// A partition found in a board definition.
+struct Part {
+    name: String,
+    id: FlashId,
+    dev_id: u8,
+    base: usize,
+    len: usize,
+}
+
+fn invalid<T: AsRef<str>>(message: T) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.as_ref())
+}
+
+/// Parse a number as written in a device tree or bsp.yml: decimal, hex with "0x", or with a "kB"
+/// or "MB" suffix.  Numbers that don't fit in a usize are rejected.
+fn parse_int(text: &str) -> io::Result<usize> {
+    let text = text.trim();
+    let bad = || invalid(format!("bad number {:?}", text));
+
+    let (digits, scale) = if text.ends_with("kB") {
+        (&text[..text.len() - 2], 1024)
+    } else if text.ends_with("MB") {
+        (&text[..text.len() - 2], 1024 * 1024)
+    } else {
+        (text, 1)
+    };
+    let (digits, radix) = if digits.starts_with("0x") || digits.starts_with("0X") {
+        (&digits[2..], 16)
+    } else {
+        (digits, 10)
+    };
+    if digits.is_empty() {
+        return Err(bad());
+    }
+
+    let mut value: usize = 0;
+    for ch in digits.chars() {
+        let digit = ch.to_digit(radix).ok_or_else(&bad)?;
+        value = value.checked_mul(radix as usize)
+            .and_then(|v| v.checked_add(digit as usize))
+            .ok_or_else(&bad)?;
+    }
+    value.checked_mul(scale).ok_or_else(&bad)
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Cells(Vec<String>),
+    Punct(char),
+}
+
+/// Remove the comments from a device tree source, keeping the line breaks.
+fn strip_comments(text: &str) -> io::Result<String> {
+    let mut result = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match (ch, chars.peek().cloned()) {
+            ('/', Some('/')) => {
+                while chars.peek().map_or(false, |&c| c != '\n') {
+                    chars.next();
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut last = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if last == '*' => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                result.push(c);
+                            }
+                            last = c;
+                        }
+                        None => return Err(invalid("unterminated comment in device tree")),
+                    }
+                }
+            }
+            _ => result.push(ch),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Split a device tree source into tokens, dropping comments and preprocessor lines.
+fn tokenize(text: &str) -> io::Result<Vec<Token>> {
+    let mut tokens = vec![];
+
+    for line in strip_comments(text)?.lines() {
+        let trimmed = line.trim();
+        if ["#include", "#define", "#if", "#else", "#endif", "#undef"].iter()
+            .any(|p| trimmed.starts_with(p))
+        {
+            continue;
+        }
+
+        let mut chars = trimmed.chars().peekable();
+        while let Some(ch) = chars.next() {
+            match ch {
+                ' ' | '\t' | '\r' => (),
+                '{' | '}' | ';' | '=' | ':' | ',' => tokens.push(Token::Punct(ch)),
+                '"' => {
+                    let mut s = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => s.extend(chars.next()),
+                            Some(c) => s.push(c),
+                            None => return Err(invalid("unterminated string in device tree")),
+                        }
+                    }
+                    tokens.push(Token::Str(s));
+                }
+                '<' => {
+                    let cells: String = chars.by_ref().take_while(|&c| c != '>').collect();
+                    tokens.push(Token::Cells(cells.split_whitespace().map(String::from)
+                                             .collect()));
+                }
+                _ => {
+                    // Names can contain commas, as in "zephyr,code-partition".
+                    let mut word = ch.to_string();
+                    while let Some(&c) = chars.peek() {
+                        if c.is_whitespace() || "{};=:<\"".contains(c) {
+                            break;
+                        }
+                        word.push(c);
+                        chars.next();
+                    }
+                    tokens.push(Token::Word(word));
+                }
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// A node of the device tree.  Only the properties partitions need are kept.
+#[derive(Debug)]
+struct Node {
+    name: String,
+    label: Option<String>,
+    props: Vec<(String, Vec<Token>)>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    /// The name of the node without its unit address.
+    fn base_name(&self) -> &str {
+        self.name.split('@').next().unwrap()
+    }
+
+    /// Whether this node has the given label or name.  A node that refers to another, as in
+    /// `&flash0 { ... }`, is named by the label it refers to.
+    fn is_named(&self, name: &str) -> bool {
+        self.label.as_ref().map_or(false, |l| l == name) ||
+            self.name.trim_left_matches('&') == name
+    }
+
+    /// Collect the nodes with the given base name, along with their parents, in the order they
+    /// appear.
+    fn find<'a>(&'a self, name: &str, found: &mut Vec<(&'a Node, &'a Node)>) {
+        for child in &self.children {
+            if child.base_name() == name {
+                found.push((self, child));
+            } else {
+                child.find(name, found);
+            }
+        }
+    }
+
+    fn prop(&self, name: &str) -> Option<&Vec<Token>> {
+        self.props.iter().find(|p| p.0 == name).map(|p| &p.1)
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        match self.prop(name).and_then(|v| v.first()) {
+            Some(&Token::Str(ref s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn cells(&self, name: &str) -> Option<&Vec<String>> {
+        match self.prop(name).and_then(|v| v.first()) {
+            Some(&Token::Cells(ref cells)) => Some(cells),
+            _ => None,
+        }
+    }
+}
+
+/// Parse the body of a node, up to its closing brace or the end of the source.
+fn parse_node(name: String, label: Option<String>, tokens: &[Token],
+              pos: &mut usize) -> io::Result<Node> {
+    let mut node = Node {
+        name: name,
+        label: label,
+        props: vec![],
+        children: vec![],
+    };
+    let mut label = None;
+
+    while *pos < tokens.len() {
+        let word = match tokens[*pos] {
+            Token::Punct('}') => break,
+            Token::Punct(';') => {
+                *pos += 1;
+                continue;
+            }
+            Token::Word(ref word) => word.clone(),
+            ref token => return Err(invalid(format!("unexpected {:?} in device tree", token))),
+        };
+        *pos += 1;
+
+        match tokens.get(*pos) {
+            // A label for the node that follows.
+            Some(&Token::Punct(':')) => {
+                *pos += 1;
+                label = Some(word);
+            }
+            Some(&Token::Punct('{')) => {
+                *pos += 1;
+                let child = parse_node(word, label.take(), tokens, pos)?;
+                if tokens.get(*pos) != Some(&Token::Punct('}')) {
+                    return Err(invalid(format!("node {} is not closed", child.name)));
+                }
+                *pos += 1;
+                node.children.push(child);
+            }
+            Some(&Token::Punct('=')) => {
+                *pos += 1;
+                let mut value = vec![];
+                loop {
+                    match tokens.get(*pos) {
+                        Some(&Token::Punct(';')) => break,
+                        Some(&Token::Punct(',')) => (),
+                        Some(&Token::Str(ref s)) => value.push(Token::Str(s.clone())),
+                        Some(&Token::Cells(ref c)) => value.push(Token::Cells(c.clone())),
+                        Some(&Token::Word(ref w)) => value.push(Token::Word(w.clone())),
+                        _ => return Err(invalid(format!("property {} is not terminated",
+                                                        word))),
+                    }
+                    *pos += 1;
+                }
+                node.props.push((word, value));
+            }
+            // A property without a value, or a directive such as /dts-v1/.
+            _ => node.props.push((word, vec![])),
+        }
+    }
+
+    Ok(node)
+}
+
+#[cfg(test)]
+mod test {
+    use area::{AreaDesc, FlashId};
+    use simflash::{SimFlash, SimFlashMap};
+
+    fn flashmap() -> SimFlashMap {
+        let mut flashmap = SimFlashMap::new();
+        flashmap.insert(0, SimFlash::new(vec![4096; 128], 1));
+        flashmap.insert(1, SimFlash::new(vec![4096; 256], 1));
+        flashmap
+    }
+
+    #[test]
+    fn test_dts() {
+        let areadesc = AreaDesc::from_dts(&flashmap(), r#"
+            /dts-v1/;
+            #include <nordic/nrf52840.dtsi>
+
+            / {
+                model = "Test board";
+                chosen {
+                    zephyr,code-partition = &slot0_partition;
+                };
+            };
+
+            &flash0 {
+                /*
+                 * The bootloader is first, the scratch area last.
+                 */
+                partitions {
+                    compatible = "fixed-partitions";
+                    #address-cells = <1>;
+                    #size-cells = <1>;
+
+                    boot_partition: partition@0 {
+                        label = "mcuboot";
+                        reg = <0x00000000 0x8000>;
+                        read-only;
+                    };
+                    slot0_partition: partition@8000 {
+                        label = "image-0";
+                        reg = <0x00008000 0x00068000>;
+                    };
+                    scratch_partition: partition@70000 {
+                        label = "image-scratch";
+                        reg = <0x00070000 0x0000d000>; // Small
+                    };
+                    storage_partition: partition@7d000 {
+                        label = "storage";
+                        reg = <0x0007d000 0x00003000>;
+                    };
+                };
+            };
+
+            &spi_flash {
+                partitions {
+                    compatible = "fixed-partitions";
+                    #address-cells = <1>;
+                    #size-cells = <1>;
+
+                    slot1_partition: partition@0 {
+                        label = "image-1";
+                        reg = <0 425984>;
+                    };
+                };
+            };
+        "#, &["flash0", "spi_flash"]).unwrap();
+
+        assert_eq!(areadesc.find(FlashId::BootLoader), (0, 0x8000, 0));
+        assert_eq!(areadesc.find(FlashId::Image0), (0x8000, 0x68000, 0));
+        assert_eq!(areadesc.find(FlashId::Image1), (0, 0x68000, 1));
+        assert_eq!(areadesc.find(FlashId::ImageScratch), (0x70000, 0xd000, 0));
+    }
+
+    #[test]
+    fn test_dts_devices() {
+        // The device comes from the flash node, named by its label or its name, and not from the
+        // order of the partitions.
+        let dts = r#"
+            soc {
+                spi: flash@2000 {
+                    partitions {
+                        partition@0 { label = "image-1"; reg = <0 0x8000>; };
+                    };
+                };
+                flash@0 {
+                    partitions {
+                        partition@0 { label = "image-0"; reg = <0 0x8000>; };
+                    };
+                };
+            };
+        "#;
+
+        let areadesc = AreaDesc::from_dts(&flashmap(), dts, &["flash@0", "spi"]).unwrap();
+        assert_eq!(areadesc.find(FlashId::Image0), (0, 0x8000, 0));
+        assert_eq!(areadesc.find(FlashId::Image1), (0, 0x8000, 1));
+
+        assert!(AreaDesc::from_dts(&flashmap(), dts, &["flash@0"]).is_err());
+    }
+
+    #[test]
+    fn test_bad_dts() {
+        let part = |label: &str, reg: &str| {
+            format!("&flash0 {{ partitions {{ partition@0 {{ label = \"{}\"; reg = <{}>; }}; \
+                     }}; }};", label, reg)
+        };
+        let from_dts = |text: &str| AreaDesc::from_dts(&flashmap(), text, &["flash0"]);
+
+        assert!(from_dts(&part("image-0", "0 0x8000")).is_ok());
+        assert!(from_dts(&part("image-0", "0 0x8000 0")).is_err());
+        assert!(from_dts(&part("image-0", "0 eight")).is_err());
+        assert!(from_dts("&flash0 { partitions { };").is_err());
+        assert!(from_dts("/* &flash0 { };").is_err());
+        assert!(from_dts(&part("image-0", "0 0x8000").replace("flash0", "flash1")).is_err());
+    }
+
+    #[test]
+    fn test_parse_int() {
+        use super::parse_int;
+        use std::usize;
+
+        assert_eq!(parse_int("4096").unwrap(), 4096);
+        assert_eq!(parse_int(" 0x1000 ").unwrap(), 0x1000);
+        assert_eq!(parse_int("16kB").unwrap(), 16 * 1024);
+        assert_eq!(parse_int("0x2MB").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_int(&usize::MAX.to_string()).unwrap(), usize::MAX);
+
+        assert!(parse_int("").is_err());
+        assert!(parse_int("0x").is_err());
+        assert!(parse_int("kB").is_err());
+        assert!(parse_int("-1").is_err());
+        assert!(parse_int("0x1g").is_err());
+        assert!(parse_int(&format!("{}0", usize::MAX)).is_err());
+        assert!(parse_int(&format!("{}kB", usize::MAX / 1024 + 1)).is_err());
+    }
+
+    #[test]
+    fn test_bsp_yml() {
+        let areadesc = AreaDesc::from_bsp_yml(&flashmap(), r#"
+bsp.name: "Test board"
+bsp.flash_map:
+    areas:
+        # System areas.
+        FLASH_AREA_BOOTLOADER:
+            device: 0
+            offset: 0x00000000
+            size: 32kB
+        FLASH_AREA_IMAGE_0:
+            device: 0
+            offset: 0x00008000
+            size: 416kB
+        FLASH_AREA_IMAGE_1:
+            device: 1
+            offset: 0x00000000
+            size: 416kB
+        FLASH_AREA_IMAGE_SCRATCH:
+            device: 0
+            offset: 0x00070000
+            size: 52kB
+
+        # User areas.
+        FLASH_AREA_REBOOT_LOG:
+            user_id: 0
+            device: 0
+            offset: 0x0007d000
+            size: 4kB
+        FLASH_AREA_USER_STORE:
+            user_id: 1
+            device: 0
+            offset: 0x0007e000
+            size: 8kB
+"#).unwrap();
+
+        assert_eq!(areadesc.find(FlashId::BootLoader), (0, 0x8000, 0));
+        assert_eq!(areadesc.find(FlashId::Image0), (0x8000, 0x68000, 0));
+        assert_eq!(areadesc.find(FlashId::Image1), (0, 0x68000, 1));
+        assert_eq!(areadesc.find(FlashId::ImageScratch), (0x70000, 0xd000, 0));
+        assert_eq!(areadesc.find(FlashId::RebootLog), (0x7d000, 0x1000, 0));
+    }
+
+    #[test]
+    fn test_bad_bsp_yml() {
+        let area = |device: &str| {
+            format!("bsp.flash_map:\n  areas:\n    FLASH_AREA_IMAGE_0:\n      device: {}\n      \
+                     offset: 0x8000\n      size: 16kB\n", device)
+        };
+
+        assert!(AreaDesc::from_bsp_yml(&flashmap(), &area("0")).is_ok());
+        assert!(AreaDesc::from_bsp_yml(&flashmap(), &area("2")).is_err());
+        assert!(AreaDesc::from_bsp_yml(&flashmap(), &area("internal")).is_err());
+        assert!(AreaDesc::from_bsp_yml(&flashmap(), "bsp.name: \"Test board\"\n").is_err());
+    }
+}
@@ -4,36 +4,38 @@ use area::AreaDesc;
 use simflash::Flash;
 use libc;
 use api;
+use std::cmp;
+use std::collections::HashMap;
 
-/// Invoke the bootloader on this flash device.
-pub fn boot_go(flash: &mut Flash, areadesc: &AreaDesc) -> i32 {
-    unsafe { api::set_flash(flash) };
+/// Invoke the bootloader on these flash devices, indexed by the device ids used in the area
+/// descriptor.  Returns 0 on success, -0x13579 if the boot was interrupted by the flash counter,
+/// or -0x2468a if an assertion in the bootloader failed.  The bootloader's state is kept per
+/// thread, so boots can run from several threads at once.
+pub fn boot_go<F: Flash>(flashmap: &mut HashMap<u8, F>, areadesc: &AreaDesc) -> i32 {
+    for (&dev_id, flash) in flashmap.iter_mut() {
+        unsafe { api::set_flash(dev_id, flash) };
+    }
     let result = unsafe { raw::invoke_boot_go(&areadesc.get_c() as *const _) as i32 };
     unsafe { api::clear_flash(); };
     result
 }
 
-/// Setter/getter for the flash counter.  This isn't thread safe.
+/// Setter/getter for the flash counter.  Each thread has its own counter, which applies to the
+/// boots run from that thread.
 pub fn get_flash_counter() -> i32 {
-    unsafe { raw::flash_counter as i32 }
+    unsafe { raw::sim_get_flash_counter() as i32 }
 }
 
 /// Set the flash counter.  Zero indicates the flash should not be interrupted.  The counter will
 /// then go negative for each flash operation.
 pub fn set_flash_counter(counter: i32) {
-    unsafe { raw::flash_counter = counter as libc::c_int };
-}
-
-pub fn boot_trailer_sz() -> u32 {
-    unsafe { raw::boot_slots_trailer_sz(raw::sim_flash_align) }
+    unsafe { raw::sim_set_flash_counter(counter as libc::c_int) };
 }
 
-pub fn get_sim_flash_align() -> u8 {
-    unsafe { raw::sim_flash_align }
-}
-
-pub fn set_sim_flash_align(align: u8) {
-    unsafe { raw::sim_flash_align = align };
+/// The size of the trailer at the end of an image slot, for a device with the given write
+/// alignment.
+pub fn boot_trailer_sz(align: u8) -> u32 {
+    unsafe { raw::boot_slots_trailer_sz(align) }
 }
 
 pub fn boot_magic_sz() -> usize {
@@ -44,6 +46,28 @@ pub fn boot_max_align() -> usize {
     unsafe { raw::BOOT_MAX_ALIGN as usize }
 }
 
+/// The space taken by the magic at the end of the trailer.  It is padded at the front to the
+/// largest alignment, when that is larger than the magic.
+pub fn boot_magic_area_sz() -> usize {
+    cmp::max(boot_magic_sz(), boot_max_align())
+}
+
+/// The value that the bootloader expects erased flash to read as.  It compares the magic and the
+/// flags in the image trailer against this to tell whether they have been written.
+pub fn boot_erased_val() -> u8 {
+    0xff
+}
+
+/// The most sectors the bootloader can handle in an image slot (`BOOT_MAX_IMG_SECTORS`).
+pub fn boot_max_img_sectors() -> usize {
+    unsafe { raw::sim_max_img_sectors as usize }
+}
+
+/// The number of public keys the bootloader was built to trust.  Valid key ids are below this.
+pub fn boot_key_count() -> usize {
+    unsafe { raw::bootutil_key_cnt as usize }
+}
+
 mod raw {
     use area::CAreaDesc;
     use libc;
@@ -53,12 +77,15 @@ mod raw {
         // be any way to get rid of this warning.  See https://github.com/rust-lang/rust/issues/34798
         // for information and tracking.
         pub fn invoke_boot_go(areadesc: *const CAreaDesc) -> libc::c_int;
-        pub static mut flash_counter: libc::c_int;
+        pub fn sim_get_flash_counter() -> libc::c_int;
+        pub fn sim_set_flash_counter(counter: libc::c_int);
 
-        pub static mut sim_flash_align: u8;
         pub fn boot_slots_trailer_sz(min_write_sz: u8) -> u32;
 
         pub static BOOT_MAGIC_SZ: u32;
         pub static BOOT_MAX_ALIGN: u32;
+        pub static sim_max_img_sectors: u32;
+
+        pub static bootutil_key_cnt: libc::c_int;
     }
 }
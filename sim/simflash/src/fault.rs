@@ -0,0 +1,213 @@
+//! Fault injection
+//!
+//! A `FaultFlash` wraps another flash device, and passes operations through to it, except for those
+//! chosen to fail.  Writes and erases are counted from 1, in the order they are made, so that a
+//! fault can be placed at a particular step of an upgrade.
+
+use super::{ErrorKind, Flash, Result, SectorIter};
+use std::cell::Cell;
+
+/// A single fault to inject.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// The nth write fails, without changing the flash.
+    FailWrite(usize),
+    /// The nth erase fails, without changing the flash.
+    FailErase(usize),
+    /// Only the given number of bytes at the start of the nth write are written, then it fails.
+    /// The number is rounded down to the device's alignment, as the device only ever completes
+    /// whole words.
+    PartialWrite(usize, usize),
+    /// Every read of the byte at the given offset has the bits in the mask inverted.
+    BitFlip(usize, u8),
+}
+
+#[derive(Clone)]
+pub struct FaultFlash<F> {
+    flash: F,
+    faults: Vec<Fault>,
+    writes: usize,
+    erases: usize,
+    // The number of times a fault has taken effect.  Reads update this too, so it needs to be a
+    // Cell.
+    injected: Cell<usize>,
+}
+
+impl<F: Flash> FaultFlash<F> {
+    /// Wrap a device, initially without any faults.
+    pub fn new(flash: F) -> FaultFlash<F> {
+        FaultFlash {
+            flash: flash,
+            faults: vec![],
+            writes: 0,
+            erases: 0,
+            injected: Cell::new(0),
+        }
+    }
+
+    pub fn add_fault(&mut self, fault: Fault) {
+        self.faults.push(fault);
+    }
+
+    /// The number of writes attempted, including those that failed.
+    pub fn write_count(&self) -> usize {
+        self.writes
+    }
+
+    /// The number of erases attempted, including those that failed.
+    pub fn erase_count(&self) -> usize {
+        self.erases
+    }
+
+    /// The number of times one of the faults has taken effect: a write or erase failed, or a read
+    /// returned a flipped bit.  A fault that never happens tells nothing about the bootloader.
+    pub fn injected(&self) -> usize {
+        self.injected.get()
+    }
+
+    // Count a fault that took effect.
+    fn inject(&self) {
+        self.injected.set(self.injected.get() + 1);
+    }
+
+    /// Remove the wrapper, returning the device with any changes made through it.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+}
+
+impl<F: Flash> Flash for FaultFlash<F> {
+    fn erase(&mut self, offset: usize, len: usize) -> Result<()> {
+        self.erases += 1;
+        for fault in &self.faults {
+            if *fault == Fault::FailErase(self.erases) {
+                self.inject();
+                bail!(einjected(format!("erase {} at 0x{:x}", self.erases, offset)));
+            }
+        }
+        self.flash.erase(offset, len)
+    }
+
+    fn write(&mut self, offset: usize, payload: &[u8]) -> Result<()> {
+        self.writes += 1;
+        for fault in &self.faults {
+            match *fault {
+                Fault::FailWrite(n) if n == self.writes => {
+                    self.inject();
+                    bail!(einjected(format!("write {} at 0x{:x}", n, offset)));
+                }
+                Fault::PartialWrite(n, len) if n == self.writes => {
+                    let len = if len < payload.len() { len } else { payload.len() };
+                    let len = len - len % self.flash.align();
+                    self.inject();
+                    // The write fails either way, and it is the injected failure the caller
+                    // needs to see.
+                    if len > 0 {
+                        let _ = self.flash.write(offset, &payload[..len]);
+                    }
+                    bail!(einjected(format!("write {} at 0x{:x} after {} bytes", n, offset, len)));
+                }
+                _ => (),
+            }
+        }
+        self.flash.write(offset, payload)
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) -> Result<()> {
+        self.flash.read(offset, data)?;
+        for fault in &self.faults {
+            if let Fault::BitFlip(pos, mask) = *fault {
+                if pos >= offset && pos < offset + data.len() {
+                    data[pos - offset] ^= mask;
+                    self.inject();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn interrupted_write(&mut self, offset: usize, payload: &[u8]) -> Result<()> {
+        self.flash.interrupted_write(offset, payload)
+    }
+
+    fn interrupted_erase(&mut self, offset: usize, len: usize) -> Result<()> {
+        self.flash.interrupted_erase(offset, len)
+    }
+
+    fn sector_iter(&self) -> SectorIter {
+        self.flash.sector_iter()
+    }
+
+    fn device_size(&self) -> usize {
+        self.flash.device_size()
+    }
+
+    fn align(&self) -> usize {
+        self.flash.align()
+    }
+}
+
+fn einjected(message: String) -> ErrorKind {
+    ErrorKind::Injected(message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Fault, FaultFlash};
+    use {Error, ErrorKind, Flash, SimFlash};
+
+    #[test]
+    fn test_faults() {
+        let mut flash = FaultFlash::new(SimFlash::new(vec![4096usize; 4], 4));
+        flash.add_fault(Fault::FailWrite(2));
+        flash.add_fault(Fault::PartialWrite(3, 4));
+        flash.add_fault(Fault::FailErase(1));
+        flash.add_fault(Fault::BitFlip(0x1001, 0x81));
+
+        flash.write(0, &[1; 4]).unwrap();
+        match flash.write(4, &[2; 4]) {
+            Err(Error(ErrorKind::Injected(_), _)) => (),
+            r => panic!("Expected injected write failure: {:?}", r),
+        }
+        assert!(flash.write(0x1000, &[3; 8]).is_err());
+        assert!(flash.erase(0, 4096).is_err());
+        flash.erase(0x2000, 4096).unwrap();
+        assert_eq!(flash.write_count(), 3);
+        assert_eq!(flash.erase_count(), 2);
+        assert_eq!(flash.injected(), 3);
+
+        let mut buf = [0; 8];
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [1, 1, 1, 1, 0xff, 0xff, 0xff, 0xff]);
+        flash.read(0x1000, &mut buf).unwrap();
+        assert_eq!(buf, [3, 0x82, 3, 3, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(flash.injected(), 4);
+
+        // The flipped bit is only seen when reading through the wrapper.
+        let flash = flash.into_inner();
+        flash.read(0x1000, &mut buf).unwrap();
+        assert_eq!(buf, [3, 3, 3, 3, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_misaligned_partial_write() {
+        let mut flash = FaultFlash::new(SimFlash::new(vec![4096usize; 4], 4));
+        flash.add_fault(Fault::PartialWrite(1, 6));
+        flash.add_fault(Fault::PartialWrite(2, 3));
+
+        // Only whole words of the prefix are written, and the failure is always the injected one.
+        for &offset in &[0, 0x1000] {
+            match flash.write(offset, &[5; 8]) {
+                Err(Error(ErrorKind::Injected(_), _)) => (),
+                r => panic!("Expected injected write failure: {:?}", r),
+            }
+        }
+        assert_eq!(flash.injected(), 2);
+
+        let mut buf = [0; 8];
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [5, 5, 5, 5, 0xff, 0xff, 0xff, 0xff]);
+        flash.read(0x1000, &mut buf).unwrap();
+        assert_eq!(buf, [0xff; 8]);
+    }
+}
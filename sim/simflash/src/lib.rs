@@ -4,14 +4,23 @@
 //! These generally can be written as individual bytes, but must be erased in larger units.
 
 #[macro_use] extern crate error_chain;
+extern crate rand;
+mod fault;
 mod pdump;
+mod trace;
 
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::iter::Enumerate;
 use std::path::Path;
 use std::slice;
 use pdump::HexDump;
+use rand::Rng;
+
+pub use fault::{Fault, FaultFlash};
+pub use trace::{Op, Trace, TraceFlash};
 
 error_chain! {
     errors {
@@ -19,9 +28,29 @@ error_chain! {
             description("Offset is out of bounds")
             display("Offset out of bounds: {}", t)
         }
-        Write(t: String) {
-            description("Invalid write")
-            display("Invalid write: {}", t)
+        Misaligned(t: String) {
+            description("Misaligned write")
+            display("Misaligned write: {}", t)
+        }
+        NotErased(offset: usize) {
+            description("Write to unerased location")
+            display("Write to unerased location at 0x{:x}", offset)
+        }
+        Worn(sector: usize) {
+            description("Sector worn out")
+            display("Sector {} worn out", sector)
+        }
+        Injected(t: String) {
+            description("Injected fault")
+            display("Injected fault: {}", t)
+        }
+        Trace(t: String) {
+            description("Invalid trace")
+            display("Invalid trace: {}", t)
+        }
+        Format(t: String) {
+            description("Invalid saved flash")
+            display("Invalid saved flash: {}", t)
         }
     }
 }
@@ -31,17 +60,82 @@ pub trait Flash {
     fn write(&mut self, offset: usize, payload: &[u8]) -> Result<()>;
     fn read(&self, offset: usize, data: &mut [u8]) -> Result<()>;
 
+    /// Apply whatever part of a write takes effect when power is lost during it.  By default,
+    /// none of it does.
+    fn interrupted_write(&mut self, _offset: usize, _payload: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Apply whatever part of an erase takes effect when power is lost during it.  By default,
+    /// none of it does.
+    fn interrupted_erase(&mut self, _offset: usize, _len: usize) -> Result<()> {
+        Ok(())
+    }
+
     fn sector_iter(&self) -> SectorIter;
     fn device_size(&self) -> usize;
+
+    /// The write alignment of the device.  Writes must start and end on a multiple of this.
+    fn align(&self) -> usize;
 }
 
 fn ebounds<T: AsRef<str>>(message: T) -> ErrorKind {
     ErrorKind::OutOfBounds(message.as_ref().to_owned())
 }
 
-#[allow(dead_code)]
-fn ewrite<T: AsRef<str>>(message: T) -> ErrorKind {
-    ErrorKind::Write(message.as_ref().to_owned())
+fn eformat<T: AsRef<str>>(message: T) -> ErrorKind {
+    ErrorKind::Format(message.as_ref().to_owned())
+}
+
+fn ealign<T: AsRef<str>>(message: T) -> ErrorKind {
+    ErrorKind::Misaligned(message.as_ref().to_owned())
+}
+
+/// How the device treats a write to a location that has been written since it was last erased.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WritePolicy {
+    /// Each location can only be written once between erases.
+    Strict,
+    /// Locations can be written again, but bits can only change from their erased value (1 to 0,
+    /// on a device that erases to 0xff), as on many NOR parts.
+    ClearBits,
+    /// Any location can be written at any time.
+    Rewrite,
+}
+
+/// How much of a write or erase takes effect when power is lost during it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PowerLoss {
+    /// The operation has no effect at all.
+    Clean,
+    /// Only the given number of bytes at the start of the operation take effect.
+    Prefix(usize),
+    /// A random number of bytes at the start of the operation take effect.
+    Random,
+}
+
+/// What happens to a sector that has been erased more times than its endurance.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WearOut {
+    /// Further erases of the sector fail.
+    Fail,
+    /// Further erases appear to succeed, but leave some bits of the sector programmed.
+    Degrade,
+}
+
+/// How long flash operations take, in microseconds.  The default takes no time at all.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Timing {
+    /// Time to erase a sector, regardless of its size.
+    pub erase_sector_us: u64,
+    /// Additional time to erase each KiB of a sector.
+    pub erase_kb_us: u64,
+    /// Time to program a single word.
+    pub program_word_us: u64,
+    /// The size of a word, in bytes.  Writes take the time of the number of words they touch.
+    pub word_size: usize,
+    /// Time to read each KiB.
+    pub read_kb_us: u64,
 }
 
 /// An emulated flash device.  It is represented as a block of bytes, and a list of the sector
@@ -53,10 +147,29 @@ pub struct SimFlash {
     sectors: Vec<usize>,
     // Alignment required for writes.
     align: usize,
+    // The value of each byte after an erase.
+    erased_val: u8,
+    policy: WritePolicy,
+    power_loss: PowerLoss,
+    // Whether an interrupted operation leaves bits that are neither in their old nor new state.
+    unstable: bool,
+    // The number of times each sector has been erased, and written to.
+    erase_counts: Vec<u32>,
+    write_counts: Vec<u32>,
+    // The number of erases each sector can take, if limited.
+    endurance: Option<(u32, WearOut)>,
+    timing: Timing,
+    // The simulated time spent in flash operations, in nanoseconds, so that small reads and the
+    // part of a KiB they cover still add up.  Reads update this too, so it needs to be a Cell.
+    elapsed: Cell<u64>,
 }
 
+/// The flash devices in a simulation, indexed by the device id that the flash areas refer to.
+pub type SimFlashMap = HashMap<u8, SimFlash>;
+
 impl SimFlash {
-    /// Given a sector size map, construct a flash device for that.
+    /// Given a sector size map, construct a flash device for that.  The device erases to 0xff, and
+    /// only allows one write to each location between erases.
     pub fn new(sectors: Vec<usize>, align: usize) -> SimFlash {
         // Verify that the alignment is a positive power of two.
         assert!(align > 0);
@@ -66,11 +179,110 @@ impl SimFlash {
         SimFlash {
             data: vec![0xffu8; total],
             write_safe: vec![true; total],
+            erase_counts: vec![0; sectors.len()],
+            write_counts: vec![0; sectors.len()],
             sectors: sectors,
             align: align,
+            erased_val: 0xff,
+            policy: WritePolicy::Strict,
+            power_loss: PowerLoss::Clean,
+            unstable: false,
+            endurance: None,
+            timing: Timing::default(),
+            elapsed: Cell::new(0),
         }
     }
 
+    /// Change the value that the device erases to.  The whole device is erased to the new value.
+    pub fn with_erased_val(mut self, erased_val: u8) -> SimFlash {
+        self.erased_val = erased_val;
+        for x in &mut self.data {
+            *x = erased_val;
+        }
+        for x in &mut self.write_safe {
+            *x = true;
+        }
+        self
+    }
+
+    /// Change how the device handles writes to locations that have already been written.
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> SimFlash {
+        self.policy = policy;
+        self
+    }
+
+    /// Change how much of an operation interrupted by a loss of power takes effect.
+    pub fn with_power_loss(mut self, power_loss: PowerLoss) -> SimFlash {
+        self.power_loss = power_loss;
+        self
+    }
+
+    /// When set, an interrupted write leaves the first byte it didn't complete partly programmed,
+    /// and an interrupted erase leaves the rest of its range partly erased.  Those bytes can't be
+    /// written again until they are erased.
+    pub fn with_unstable_bits(mut self, unstable: bool) -> SimFlash {
+        self.unstable = unstable;
+        self
+    }
+
+    /// Limit the number of times each sector can be erased.  Once a sector has been erased this many
+    /// times, further erases fail or degrade it.
+    pub fn with_endurance(mut self, limit: u32, wear_out: WearOut) -> SimFlash {
+        self.endurance = Some((limit, wear_out));
+        self
+    }
+
+    /// Set how long each flash operation takes.
+    pub fn with_timing(mut self, timing: Timing) -> SimFlash {
+        self.timing = timing;
+        self
+    }
+
+    /// The simulated time spent in flash operations so far, in microseconds.
+    pub fn elapsed_us(&self) -> u64 {
+        self.elapsed.get() / 1000
+    }
+
+    /// Restart the simulated clock from zero.
+    pub fn reset_clock(&self) {
+        self.elapsed.set(0);
+    }
+
+    // Advance the simulated clock, in nanoseconds.
+    fn spend(&self, ns: u64) {
+        self.elapsed.set(self.elapsed.get() + ns);
+    }
+
+    // The time to erase a sector of the given size, in nanoseconds.
+    fn erase_ns(&self, size: usize) -> u64 {
+        self.timing.erase_sector_us * 1000 + self.timing.erase_kb_us * size as u64 * 1000 / 1024
+    }
+
+    // The time to program the words that a write touches, in nanoseconds.
+    fn write_ns(&self, offset: usize, len: usize) -> u64 {
+        let word = self.timing.word_size;
+        if word == 0 || len == 0 {
+            return 0;
+        }
+        let words = (offset + len + word - 1) / word - offset / word;
+        words as u64 * self.timing.program_word_us * 1000
+    }
+
+    /// The number of times each sector has been erased.
+    pub fn erase_counts(&self) -> &[u32] {
+        &self.erase_counts
+    }
+
+    /// The number of writes made to each sector.  A write spanning sectors counts for each.
+    pub fn write_counts(&self) -> &[u32] {
+        &self.write_counts
+    }
+
+    /// The value of each byte after an erase.
+    pub fn erased_val(&self) -> u8 {
+        self.erased_val
+    }
+
     #[allow(dead_code)]
     pub fn dump(&self) {
         self.data.dump();
@@ -84,6 +296,190 @@ impl SimFlash {
         Ok(())
     }
 
+    /// Construct a flash device from the raw contents of a device, such as one read back from a
+    /// board, its sector map, and the value it erases to.  Bytes that read as erased are taken to
+    /// be writable, and all others to have been written.
+    pub fn from_raw(data: Vec<u8>, sectors: Vec<usize>, align: usize,
+                    erased_val: u8) -> Result<SimFlash> {
+        if align == 0 || align & (align - 1) != 0 {
+            bail!(eformat(format!("alignment {} is not a power of two", align)));
+        }
+        let total: usize = sectors.iter().sum();
+        if total != data.len() {
+            bail!(eformat(format!("sectors cover {} bytes, but the data is {} bytes",
+                                  total, data.len())));
+        }
+
+        let write_safe = data.iter().map(|&x| x == erased_val).collect();
+        Ok(SimFlash {
+            data: data,
+            write_safe: write_safe,
+            erase_counts: vec![0; sectors.len()],
+            write_counts: vec![0; sectors.len()],
+            sectors: sectors,
+            align: align,
+            erased_val: erased_val,
+            policy: WritePolicy::Strict,
+            power_loss: PowerLoss::Clean,
+            unstable: false,
+            endurance: None,
+            timing: Timing::default(),
+            elapsed: Cell::new(0),
+        })
+    }
+
+    /// Load a raw image, as written by `write_file`, with the given sector map.
+    pub fn load_raw<P: AsRef<Path>>(path: P, sectors: Vec<usize>, align: usize,
+                                    erased_val: u8) -> Result<SimFlash> {
+        let mut data = vec![];
+        File::open(path).and_then(|mut fd| fd.read_to_end(&mut data))
+            .chain_err(|| "Unable to read image file")?;
+        SimFlash::from_raw(data, sectors, align, erased_val)
+    }
+
+    /// Save the full state of this device to the given file, so that it can be restored with
+    /// `load`.  Unlike `write_file`, this includes the sector map, the alignment, the erase and
+    /// write behavior, the behavior on power loss, the endurance and timing, which bytes can be
+    /// written without an erase, the wear on each sector, and the simulated time spent so far.
+    ///
+    /// The file starts with "SIMFLASH" and a version, followed by the alignment, the erased value,
+    /// the write policy, the power loss kind and prefix length, whether bits are left unstable, the
+    /// wear out kind (0 for unlimited) and endurance, the timing, the elapsed time in nanoseconds,
+    /// the number of sectors, and the size, erase count and write count of each sector.  Times are
+    /// little-endian u64, and everything else little-endian u32.  Then comes the data, and finally one byte for
+    /// each byte of data, which is 1 if it can be written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut buf = vec![];
+        buf.extend_from_slice(SAVE_MAGIC);
+        put_u32(&mut buf, SAVE_VERSION);
+        put_u32(&mut buf, self.align as u32);
+        put_u32(&mut buf, self.erased_val as u32);
+        put_u32(&mut buf, match self.policy {
+            WritePolicy::Strict => 0,
+            WritePolicy::ClearBits => 1,
+            WritePolicy::Rewrite => 2,
+        });
+        let (kind, prefix) = match self.power_loss {
+            PowerLoss::Clean => (0, 0),
+            PowerLoss::Prefix(n) => (1, n),
+            PowerLoss::Random => (2, 0),
+        };
+        put_u32(&mut buf, kind);
+        put_u32(&mut buf, prefix as u32);
+        put_u32(&mut buf, self.unstable as u32);
+        let (kind, limit) = match self.endurance {
+            None => (0, 0),
+            Some((limit, WearOut::Fail)) => (1, limit),
+            Some((limit, WearOut::Degrade)) => (2, limit),
+        };
+        put_u32(&mut buf, kind);
+        put_u32(&mut buf, limit);
+        put_u64(&mut buf, self.timing.erase_sector_us);
+        put_u64(&mut buf, self.timing.erase_kb_us);
+        put_u64(&mut buf, self.timing.program_word_us);
+        put_u32(&mut buf, self.timing.word_size as u32);
+        put_u64(&mut buf, self.timing.read_kb_us);
+        put_u64(&mut buf, self.elapsed.get());
+        put_u32(&mut buf, self.sectors.len() as u32);
+        for (i, &size) in self.sectors.iter().enumerate() {
+            put_u32(&mut buf, size as u32);
+            put_u32(&mut buf, self.erase_counts[i]);
+            put_u32(&mut buf, self.write_counts[i]);
+        }
+        buf.extend_from_slice(&self.data);
+        buf.extend(self.write_safe.iter().map(|&safe| safe as u8));
+
+        let mut fd = File::create(path).chain_err(|| "Unable to write flash file")?;
+        fd.write_all(&buf).chain_err(|| "Unable to write to flash file")?;
+        Ok(())
+    }
+
+    /// Restore a device saved with `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SimFlash> {
+        let mut buf = vec![];
+        File::open(path).and_then(|mut fd| fd.read_to_end(&mut buf))
+            .chain_err(|| "Unable to read flash file")?;
+
+        if !buf.starts_with(SAVE_MAGIC) {
+            bail!(eformat("bad magic"));
+        }
+        let mut pos = SAVE_MAGIC.len();
+
+        let version = get_u32(&buf, &mut pos)?;
+        if version != SAVE_VERSION {
+            bail!(eformat(format!("unsupported version {}", version)));
+        }
+
+        let align = get_u32(&buf, &mut pos)? as usize;
+        let erased_val = get_u32(&buf, &mut pos)?;
+        if erased_val > 0xff {
+            bail!(eformat(format!("bad erased value {}", erased_val)));
+        }
+        let policy = match get_u32(&buf, &mut pos)? {
+            0 => WritePolicy::Strict,
+            1 => WritePolicy::ClearBits,
+            2 => WritePolicy::Rewrite,
+            p => bail!(eformat(format!("unknown write policy {}", p))),
+        };
+        let power_loss = match (get_u32(&buf, &mut pos)?, get_u32(&buf, &mut pos)?) {
+            (0, _) => PowerLoss::Clean,
+            (1, n) => PowerLoss::Prefix(n as usize),
+            (2, _) => PowerLoss::Random,
+            (k, _) => bail!(eformat(format!("unknown power loss {}", k))),
+        };
+        let unstable = get_u32(&buf, &mut pos)? != 0;
+        let endurance = match (get_u32(&buf, &mut pos)?, get_u32(&buf, &mut pos)?) {
+            (0, _) => None,
+            (1, limit) => Some((limit, WearOut::Fail)),
+            (2, limit) => Some((limit, WearOut::Degrade)),
+            (k, _) => bail!(eformat(format!("unknown wear out {}", k))),
+        };
+        let timing = Timing {
+            erase_sector_us: get_u64(&buf, &mut pos)?,
+            erase_kb_us: get_u64(&buf, &mut pos)?,
+            program_word_us: get_u64(&buf, &mut pos)?,
+            word_size: get_u32(&buf, &mut pos)? as usize,
+            read_kb_us: get_u64(&buf, &mut pos)?,
+        };
+        let elapsed = get_u64(&buf, &mut pos)?;
+        let count = get_u32(&buf, &mut pos)? as usize;
+        let mut sectors = vec![];
+        let mut erase_counts = vec![];
+        let mut write_counts = vec![];
+        for _ in 0 .. count {
+            sectors.push(get_u32(&buf, &mut pos)? as usize);
+            erase_counts.push(get_u32(&buf, &mut pos)?);
+            write_counts.push(get_u32(&buf, &mut pos)?);
+        }
+
+        let total: usize = sectors.iter().sum();
+        if buf.len() != pos + 2 * total {
+            bail!(eformat("file size does not match sector map"));
+        }
+
+        let mut flash = SimFlash::from_raw(buf[pos .. pos + total].to_vec(), sectors, align,
+                                           erased_val as u8)?;
+        flash.policy = policy;
+        flash.power_loss = power_loss;
+        flash.unstable = unstable;
+        flash.endurance = endurance;
+        flash.timing = timing;
+        flash.elapsed.set(elapsed);
+        flash.erase_counts = erase_counts;
+        flash.write_counts = write_counts;
+        flash.write_safe = buf[pos + total ..].iter().map(|&safe| safe != 0).collect();
+        Ok(flash)
+    }
+
+    // The number of bytes of an operation of the given length that complete before power is lost.
+    fn power_loss_len(&self, len: usize) -> usize {
+        match self.power_loss {
+            PowerLoss::Clean => 0,
+            PowerLoss::Prefix(n) => if n < len { n } else { len },
+            PowerLoss::Random => rand::thread_rng().gen_range(0, len + 1),
+        }
+    }
+
     // Scan the sector map, and return the base and offset within a sector for this given byte.
     // Returns None if the value is outside of the device.
     fn get_sector(&self, offset: usize) -> Option<(usize, usize)> {
@@ -97,6 +493,48 @@ impl SimFlash {
         return None;
     }
 
+    // Count a write to each sector in the given range.
+    fn count_writes(&mut self, offset: usize, len: usize) {
+        if len > 0 {
+            let (start, _) = self.get_sector(offset).unwrap();
+            let (end, _) = self.get_sector(offset + len - 1).unwrap();
+            for sector in start .. end + 1 {
+                self.write_counts[sector] += 1;
+            }
+        }
+    }
+}
+
+const SAVE_MAGIC: &'static [u8] = b"SIMFLASH";
+const SAVE_VERSION: u32 = 3;
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    for i in 0 .. 4 {
+        buf.push((value >> (8 * i)) as u8);
+    }
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    put_u32(buf, value as u32);
+    put_u32(buf, (value >> 32) as u32);
+}
+
+fn get_u32(buf: &[u8], pos: &mut usize) -> Result<u32> {
+    if buf.len() < *pos + 4 {
+        bail!(eformat("truncated header"));
+    }
+    let mut value = 0;
+    for i in 0 .. 4 {
+        value |= (buf[*pos + i] as u32) << (8 * i);
+    }
+    *pos += 4;
+    Ok(value)
+}
+
+fn get_u64(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    let low = get_u32(buf, pos)? as u64;
+    let high = get_u32(buf, pos)? as u64;
+    Ok(low | high << 32)
 }
 
 impl Flash for SimFlash {
@@ -104,7 +542,7 @@ impl Flash for SimFlash {
     /// strict, and make sure that the passed arguments are exactly at a sector boundary, otherwise
     /// return an error.
     fn erase(&mut self, offset: usize, len: usize) -> Result<()> {
-        let (_start, slen) = self.get_sector(offset).ok_or_else(|| ebounds("start"))?;
+        let (start, slen) = self.get_sector(offset).ok_or_else(|| ebounds("start"))?;
         let (end, elen) = self.get_sector(offset + len - 1).ok_or_else(|| ebounds("end"))?;
 
         if slen != 0 {
@@ -114,14 +552,44 @@ impl Flash for SimFlash {
             bail!(ebounds("end not at start of sector"));
         }
 
+        if let Some((limit, WearOut::Fail)) = self.endurance {
+            for sector in start .. end + 1 {
+                if self.erase_counts[sector] >= limit {
+                    bail!(ErrorKind::Worn(sector));
+                }
+            }
+        }
+
         for x in &mut self.data[offset .. offset + len] {
-            *x = 0xff;
+            *x = self.erased_val;
         }
 
         for x in &mut self.write_safe[offset .. offset + len] {
             *x = true;
         }
 
+        let mut base = offset;
+        for sector in start .. end + 1 {
+            let size = self.sectors[sector];
+            if let Some((limit, WearOut::Degrade)) = self.endurance {
+                if self.erase_counts[sector] >= limit {
+                    // A worn sector no longer erases every bit.  The bytes it misses can't be
+                    // written again, just as if they had been left partly programmed.
+                    let mut rng = rand::thread_rng();
+                    for pos in base .. base + size {
+                        if rng.gen_weighted_bool(16) {
+                            self.data[pos] ^= 1 << rng.gen_range(0, 8);
+                            self.write_safe[pos] = false;
+                        }
+                    }
+                }
+            }
+            self.erase_counts[sector] += 1;
+            let ns = self.erase_ns(size);
+            self.spend(ns);
+            base += size;
+        }
+
         Ok(())
     }
 
@@ -132,33 +600,115 @@ impl Flash for SimFlash {
     ///
     /// This emulates a flash device which starts out erased, with the
     /// added restriction that repeated writes to the same location
-    /// are disallowed, even if they would be safe to do.
+    /// are disallowed, even if they would be safe to do.  The write
+    /// policy can relax this restriction.
     fn write(&mut self, offset: usize, payload: &[u8]) -> Result<()> {
         if offset + payload.len() > self.data.len() {
-            panic!("Write outside of device");
+            bail!(ebounds("Write outside of device"));
         }
 
         // Verify the alignment (which must be a power of two).
         if offset & (self.align - 1) != 0 {
-            panic!("Misaligned write address");
+            bail!(ealign("address not a multiple of alignment"));
         }
 
         if payload.len() & (self.align - 1) != 0 {
-            panic!("Write length not multiple of alignment");
+            bail!(ealign("length not a multiple of alignment"));
         }
 
-        for (i, x) in &mut self.write_safe[offset .. offset + payload.len()].iter_mut().enumerate() {
-            if !(*x) {
-                panic!("Write to unerased location at 0x{:x}", offset + i);
+        for (i, &new) in payload.iter().enumerate() {
+            let old = self.data[offset + i];
+            let ok = match self.policy {
+                WritePolicy::Strict => self.write_safe[offset + i],
+                // Only the bits still at their erased value can change.
+                WritePolicy::ClearBits => (old ^ new) & (old ^ self.erased_val) == 0,
+                WritePolicy::Rewrite => true,
+            };
+            if !ok {
+                bail!(ErrorKind::NotErased(offset + i));
             }
+        }
+
+        for x in &mut self.write_safe[offset .. offset + payload.len()] {
             *x = false;
         }
 
+        let ns = self.write_ns(offset, payload.len());
+        self.spend(ns);
+
+        self.count_writes(offset, payload.len());
+
         let mut sub = &mut self.data[offset .. offset + payload.len()];
         sub.copy_from_slice(payload);
         Ok(())
     }
 
+    /// A torn write.  The part that completes is written without regard for alignment or the
+    /// write policy, since the real device wouldn't check those either.
+    fn interrupted_write(&mut self, offset: usize, payload: &[u8]) -> Result<()> {
+        if offset + payload.len() > self.data.len() {
+            bail!(ebounds("Write outside of device"));
+        }
+
+        // The write was started, so it wears the flash, however little of it completes.
+        self.count_writes(offset, payload.len());
+
+        let done = self.power_loss_len(payload.len());
+        let ns = self.write_ns(offset, done);
+        self.spend(ns);
+        self.data[offset .. offset + done].copy_from_slice(&payload[..done]);
+        for x in &mut self.write_safe[offset .. offset + done] {
+            *x = false;
+        }
+
+        if self.unstable && done < payload.len() {
+            let pos = offset + done;
+            let mask: u8 = rand::random();
+            self.data[pos] = (self.data[pos] & !mask) | (payload[done] & mask);
+            self.write_safe[pos] = false;
+        }
+
+        Ok(())
+    }
+
+    /// A partial erase.  Unlike a full erase, the range doesn't need to line up with sectors.
+    fn interrupted_erase(&mut self, offset: usize, len: usize) -> Result<()> {
+        if offset + len > self.data.len() {
+            bail!(ebounds("Erase outside of device"));
+        }
+
+        let done = self.power_loss_len(len);
+
+        // The erase takes the time of the part of it that completed.
+        if len > 0 {
+            let (start, _) = self.get_sector(offset).unwrap();
+            let (end, _) = self.get_sector(offset + len - 1).unwrap();
+            let mut ns = 0;
+            for sector in start .. end + 1 {
+                self.erase_counts[sector] += 1;
+                ns += self.erase_ns(self.sectors[sector]);
+            }
+            self.spend(ns * done as u64 / len as u64);
+        }
+
+        for x in &mut self.data[offset .. offset + done] {
+            *x = self.erased_val;
+        }
+        for x in &mut self.write_safe[offset .. offset + done] {
+            *x = true;
+        }
+
+        if self.unstable {
+            for pos in offset + done .. offset + len {
+                let mask: u8 = rand::random();
+                self.data[pos] = (self.data[pos] & !mask) | (self.erased_val & mask);
+                self.write_safe[pos] = false;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Read is simple.
     fn read(&self, offset: usize, data: &mut [u8]) -> Result<()> {
         if offset + data.len() > self.data.len() {
@@ -167,6 +717,7 @@ impl Flash for SimFlash {
 
         let sub = &self.data[offset .. offset + data.len()];
         data.copy_from_slice(sub);
+        self.spend(self.timing.read_kb_us * data.len() as u64 * 1000 / 1024);
         Ok(())
     }
 
@@ -181,6 +732,10 @@ impl Flash for SimFlash {
     fn device_size(&self) -> usize {
         self.data.len()
     }
+
+    fn align(&self) -> usize {
+        self.align
+    }
 }
 
 /// It is possible to iterate over the sectors in the device, each element returning this.
@@ -220,7 +775,9 @@ impl<'a> Iterator for SectorIter<'a> {
 
 #[cfg(test)]
 mod test {
-    use super::{Flash, SimFlash, Error, ErrorKind, Result, Sector};
+    use super::{Flash, SimFlash, Error, ErrorKind, PowerLoss, Result, Sector, Timing, WearOut,
+                WritePolicy};
+    use std::{env, fs, process};
 
     #[test]
     fn test_flash() {
@@ -272,9 +829,242 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_erased_val() {
+        let mut flash = SimFlash::new(vec![4096usize; 4], 1).with_erased_val(0);
+        let mut buf = [0xaa; 4];
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0; 4]);
+
+        flash.write(0, &[0x55]).unwrap();
+        flash.erase(0, 4096).unwrap();
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0; 4]);
+    }
+
+    #[test]
+    fn test_write_policy() {
+        let mut flash = SimFlash::new(vec![4096usize; 4], 1)
+            .with_write_policy(WritePolicy::ClearBits);
+        let mut buf = [0; 1];
+        flash.write(0, &[0xf0]).unwrap();
+        flash.write(0, &[0x30]).unwrap();
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0x30]);
+
+        // The same, on a device that erases to zero, where bits can only be set.
+        let mut flash = SimFlash::new(vec![4096usize; 4], 1)
+            .with_erased_val(0)
+            .with_write_policy(WritePolicy::ClearBits);
+        flash.write(0, &[0x0f]).unwrap();
+        flash.write(0, &[0x3f]).unwrap();
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0x3f]);
+
+        let mut flash = SimFlash::new(vec![4096usize; 4], 1)
+            .with_write_policy(WritePolicy::Rewrite);
+        flash.write(0, &[0x00]).unwrap();
+        flash.write(0, &[0xff]).unwrap();
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0xff]);
+    }
+
+    #[test]
+    fn test_large_align() {
+        let mut flash = SimFlash::new(vec![4096usize; 4], 32);
+        assert_eq!(flash.align(), 32);
+        assert!(flash.write(16, &[0; 32]).is_misaligned());
+        assert!(flash.write(32, &[0; 16]).is_misaligned());
+        flash.write(32, &[0; 64]).unwrap();
+    }
+
+    #[test]
+    fn test_write_errors() {
+        let mut flash = SimFlash::new(vec![4096usize; 4], 4);
+        assert!(flash.write(4 * 4096 - 4, &[0; 8]).is_bounds());
+        assert!(flash.write(2, &[0; 4]).is_misaligned());
+        assert!(flash.write(0, &[0; 2]).is_misaligned());
+
+        flash.write(8, &[0; 8]).unwrap();
+        assert!(flash.write(12, &[0; 4]).is_not_erased(12));
+
+        // A failed write leaves the device unchanged.
+        assert!(flash.write(4, &[1; 8]).is_not_erased(8));
+        flash.write(4, &[2; 4]).unwrap();
+
+        let mut flash = SimFlash::new(vec![4096usize; 4], 1)
+            .with_write_policy(WritePolicy::ClearBits);
+        flash.write(0, &[0xf0]).unwrap();
+        assert!(flash.write(0, &[0xf8]).is_not_erased(0));
+    }
+
+    #[test]
+    fn test_power_loss() {
+        let mut flash = SimFlash::new(vec![4096usize; 4], 4)
+            .with_power_loss(PowerLoss::Prefix(3));
+        let mut buf = [0; 8];
+
+        // A clean loss, then a torn write.
+        flash.write(0, &[1; 8]).unwrap();
+        flash.interrupted_write(8, &[2; 8]).unwrap();
+        flash.read(8, &mut buf).unwrap();
+        assert_eq!(buf, [2, 2, 2, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert!(flash.write(8, &[3; 4]).is_not_erased(8));
+
+        flash.interrupted_erase(0, 4096).unwrap();
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0xff, 0xff, 0xff, 1, 1, 1, 1, 1]);
+
+        let mut flash = SimFlash::new(vec![4096usize; 4], 4);
+        flash.interrupted_write(0, &[2; 8]).unwrap();
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0xff; 8]);
+
+        // Unstable bits can't be written without an erase.
+        let mut flash = SimFlash::new(vec![4096usize; 4], 4)
+            .with_power_loss(PowerLoss::Prefix(4))
+            .with_unstable_bits(true);
+        flash.write(0, &[0; 8]).unwrap();
+        flash.interrupted_erase(0, 4096).unwrap();
+        flash.write(0, &[1; 4]).unwrap();
+        assert!(flash.write(4, &[1; 4]).is_not_erased(4));
+    }
+
+    #[test]
+    fn test_wear() {
+        let mut flash = SimFlash::new(vec![4096usize; 4], 1)
+            .with_endurance(2, WearOut::Fail);
+        flash.erase(0, 8192).unwrap();
+        flash.erase(4096, 4096).unwrap();
+        flash.write(4095, &[0, 0]).unwrap();
+        flash.write(8192, &[0]).unwrap();
+        assert_eq!(flash.erase_counts(), &[1, 2, 0, 0]);
+        assert_eq!(flash.write_counts(), &[1, 1, 1, 0]);
+
+        flash.erase(0, 4096).unwrap();
+        assert!(flash.erase(4096, 4096).is_worn(1));
+        assert_eq!(flash.erase_counts(), &[2, 2, 0, 0]);
+
+        // A degraded sector still erases, but not completely.
+        let mut flash = SimFlash::new(vec![4096usize; 4], 1)
+            .with_endurance(0, WearOut::Degrade);
+        flash.erase(0, 4096).unwrap();
+        let mut buf = vec![0; 4096];
+        flash.read(0, &mut buf).unwrap();
+        let pos = buf.iter().position(|&x| x != 0xff).expect("sector fully erased");
+
+        // The bytes it missed can't be written over.
+        assert!(flash.write(pos, &[0x55]).is_not_erased(pos));
+
+        // Interrupted operations still wear the sectors they were started on.
+        let mut flash = SimFlash::new(vec![4096usize; 4], 1);
+        flash.interrupted_erase(0, 8192).unwrap();
+        flash.interrupted_write(4095, &[0, 0]).unwrap();
+        assert_eq!(flash.erase_counts(), &[1, 1, 0, 0]);
+        assert_eq!(flash.write_counts(), &[1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_timing() {
+        let mut flash = SimFlash::new(vec![4096usize, 4096, 8192], 1)
+            .with_timing(Timing {
+                erase_sector_us: 1000,
+                erase_kb_us: 100,
+                program_word_us: 10,
+                word_size: 4,
+                read_kb_us: 2,
+            });
+        flash.erase(0, 16384).unwrap();
+        assert_eq!(flash.elapsed_us(), 3 * 1000 + 16 * 100);
+
+        // An unaligned write touches three words.
+        flash.reset_clock();
+        flash.write(2, &[0; 8]).unwrap();
+        assert_eq!(flash.elapsed_us(), 3 * 10);
+
+        flash.reset_clock();
+        let mut buf = vec![0; 2048];
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(flash.elapsed_us(), 4);
+
+        // Small reads each take less than a microsecond, but they add up.
+        flash.reset_clock();
+        let mut buf = [0; 64];
+        for _ in 0 .. 32 {
+            flash.read(0, &mut buf).unwrap();
+        }
+        assert_eq!(flash.elapsed_us(), 4);
+
+        // Interrupted operations take the time of the part that completed.
+        let mut flash = flash.with_power_loss(PowerLoss::Prefix(2048));
+        flash.reset_clock();
+        flash.interrupted_erase(0, 4096).unwrap();
+        assert_eq!(flash.elapsed_us(), (1000 + 4 * 100) / 2);
+        flash.reset_clock();
+        flash.interrupted_write(0x2000, &[0; 4096]).unwrap();
+        assert_eq!(flash.elapsed_us(), 512 * 10);
+    }
+
+    #[test]
+    fn test_save() {
+        let path = env::temp_dir().join(format!("simflash-test-{}.bin", process::id()));
+
+        let mut f1 = SimFlash::new(vec![16 * 1024, 16 * 1024, 64 * 1024, 128 * 1024], 4);
+        f1.erase(0, 16 * 1024).unwrap();
+        f1.write(0x100, &[1, 2, 3, 4]).unwrap();
+        f1.write(0x8000, &[0xff; 8]).unwrap();
+        f1.policy = WritePolicy::ClearBits;
+        let mut f1 = f1.with_power_loss(PowerLoss::Prefix(6))
+            .with_unstable_bits(true)
+            .with_endurance(10_000, WearOut::Degrade)
+            .with_timing(Timing {
+                erase_sector_us: 1000,
+                erase_kb_us: 100,
+                program_word_us: 10,
+                word_size: 4,
+                read_kb_us: 2,
+            });
+        f1.erase(16 * 1024, 16 * 1024).unwrap();
+        f1.save(&path).unwrap();
+
+        let mut f2 = SimFlash::load(&path).unwrap();
+        assert_eq!(f2.data, f1.data);
+        assert_eq!(f2.write_safe, f1.write_safe);
+        assert_eq!(f2.sectors, f1.sectors);
+        assert_eq!(f2.align, f1.align);
+        assert_eq!(f2.erased_val, f1.erased_val);
+        assert_eq!(f2.policy, f1.policy);
+        assert_eq!(f2.power_loss, f1.power_loss);
+        assert_eq!(f2.unstable, f1.unstable);
+        assert_eq!(f2.endurance, f1.endurance);
+        assert_eq!(f2.timing, f1.timing);
+        assert_eq!(f2.elapsed_us(), f1.elapsed_us());
+        assert_eq!(f2.erase_counts, f1.erase_counts);
+        assert_eq!(f2.write_counts, f1.write_counts);
+
+        // A raw image loses track of the 0xff bytes that were written.
+        f1.write_file(&path).unwrap();
+        let f3 = SimFlash::load_raw(&path, f1.sectors.clone(), 4, 0xff).unwrap();
+        assert_eq!(f3.data, f1.data);
+        assert!(f3.write_safe[0x8000]);
+        assert!(!f3.write_safe[0x100]);
+
+        assert!(SimFlash::load(&path).is_format());
+        assert!(SimFlash::load_raw(&path, vec![4096], 4, 0xff).is_format());
+        fs::remove_file(&path).unwrap();
+
+        // The restored device behaves like the original.
+        f2.erase(0, 16 * 1024).unwrap();
+        f2.write(0x100, &[5, 6, 7, 8]).unwrap();
+    }
+
     // Helper checks for the result type.
     trait EChecker {
         fn is_bounds(&self) -> bool;
+        fn is_misaligned(&self) -> bool;
+        fn is_not_erased(&self, offset: usize) -> bool;
+        fn is_worn(&self, sector: usize) -> bool;
+        fn is_format(&self) -> bool;
     }
 
     impl<T> EChecker for Result<T> {
@@ -285,5 +1075,33 @@ mod test {
                 _ => false,
             }
         }
+
+        fn is_misaligned(&self) -> bool {
+            match *self {
+                Err(Error(ErrorKind::Misaligned(_), _)) => true,
+                _ => false,
+            }
+        }
+
+        fn is_not_erased(&self, offset: usize) -> bool {
+            match *self {
+                Err(Error(ErrorKind::NotErased(off), _)) => off == offset,
+                _ => false,
+            }
+        }
+
+        fn is_worn(&self, sector: usize) -> bool {
+            match *self {
+                Err(Error(ErrorKind::Worn(s), _)) => s == sector,
+                _ => false,
+            }
+        }
+
+        fn is_format(&self) -> bool {
+            match *self {
+                Err(Error(ErrorKind::Format(_), _)) => true,
+                _ => false,
+            }
+        }
     }
 }
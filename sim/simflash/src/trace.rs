@@ -0,0 +1,284 @@
+//! Flash operation tracing
+//!
+//! A `TraceFlash` wraps another flash device, and records each operation made through it that
+//! succeeds.  The trace can be saved as text, one operation per line, to compare what different
+//! versions of the bootloader do to the flash, and can be replayed against another device.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use super::{ErrorKind, Flash, Result, ResultExt, SectorIter};
+
+/// A single flash operation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+    Erase { offset: usize, len: usize },
+    Write { offset: usize, data: Vec<u8> },
+    /// A read, along with the data it returned.
+    Read { offset: usize, data: Vec<u8> },
+}
+
+impl Op {
+    fn offset(&self) -> usize {
+        match *self {
+            Op::Erase { offset, .. } | Op::Write { offset, .. } | Op::Read { offset, .. } => offset,
+        }
+    }
+}
+
+/// A sequence of flash operations, in the order they were made.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Trace {
+    pub ops: Vec<Op>,
+    /// The name of the flash area that each operation was made in, if known.  There is one entry
+    /// for each of `ops`.
+    pub areas: Vec<Option<String>>,
+}
+
+impl Trace {
+    /// Add an operation, made in an unknown area.
+    pub fn push(&mut self, op: Op) {
+        self.ops.push(op);
+        self.areas.push(None);
+    }
+
+    /// Name the flash area of each operation, using the given function to find the area
+    /// containing its offset.
+    pub fn name_areas(&mut self, area: &Fn(usize) -> Option<String>) {
+        self.areas = self.ops.iter().map(|op| area(op.offset())).collect();
+    }
+
+    /// Save the trace to a file.  Each line gives the operation, the offset, the length, the name
+    /// of the flash area containing the offset (or "-"), a hash of the data, and the data itself.
+    /// Everything but the data is enough to compare two traces, and is at the start of the line so
+    /// that it can be cut out.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut out = vec![];
+        for (op, area) in self.ops.iter().zip(&self.areas) {
+            let (name, len, data) = match *op {
+                Op::Erase { len, .. } => ("erase", len, None),
+                Op::Write { ref data, .. } => ("write", data.len(), Some(data)),
+                Op::Read { ref data, .. } => ("read", data.len(), Some(data)),
+            };
+            let area = area.as_ref().map(|a| &a[..]).unwrap_or("-");
+            let (hash, hex) = match data {
+                Some(data) => (format!("{:016x}", fnv1a(data)), to_hex(data)),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            out.push(format!("{} 0x{:08x} {} {} {} {}", name, op.offset(), len, area, hash, hex));
+        }
+
+        let mut fd = File::create(path).chain_err(|| "Unable to write trace file")?;
+        for line in out {
+            writeln!(fd, "{}", line).chain_err(|| "Unable to write to trace file")?;
+        }
+        Ok(())
+    }
+
+    /// Load a trace written by `save`, including the area names.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Trace> {
+        let fd = File::open(path).chain_err(|| "Unable to read trace file")?;
+        let mut trace = Trace::default();
+        for (num, line) in BufReader::new(fd).lines().enumerate() {
+            let line = line.chain_err(|| "Unable to read trace file")?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 6 {
+                bail!(etrace(format!("line {}: expecting 6 fields", num + 1)));
+            }
+
+            let offset = parse_offset(fields[1])
+                .ok_or_else(|| etrace(format!("line {}: bad offset", num + 1)))?;
+            let len = fields[2].parse::<usize>()
+                .map_err(|_| etrace(format!("line {}: bad length", num + 1)))?;
+            let data = from_hex(fields[5]);
+            let op = match (fields[0], data) {
+                ("erase", _) => Op::Erase { offset: offset, len: len },
+                ("write", Some(data)) => Op::Write { offset: offset, data: data },
+                ("read", Some(data)) => Op::Read { offset: offset, data: data },
+                _ => bail!(etrace(format!("line {}: bad operation", num + 1))),
+            };
+            match op {
+                Op::Write { ref data, .. } | Op::Read { ref data, .. } if data.len() != len => {
+                    bail!(etrace(format!("line {}: length does not match data", num + 1)));
+                }
+                _ => (),
+            }
+            trace.ops.push(op);
+            trace.areas.push(if fields[3] == "-" { None } else { Some(fields[3].to_string()) });
+        }
+
+        Ok(trace)
+    }
+
+    /// Make each of the operations against the given device.  Reads must return the same data as
+    /// when the trace was recorded.
+    pub fn replay(&self, flash: &mut Flash) -> Result<()> {
+        for (num, op) in self.ops.iter().enumerate() {
+            match *op {
+                Op::Erase { offset, len } => flash.erase(offset, len)?,
+                Op::Write { offset, ref data } => flash.write(offset, data)?,
+                Op::Read { offset, ref data } => {
+                    let mut buf = vec![0; data.len()];
+                    flash.read(offset, &mut buf)?;
+                    if buf != *data {
+                        bail!(etrace(format!("read {} at 0x{:x} differs", num, offset)));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A flash device that records the operations made on it.
+pub struct TraceFlash<F> {
+    flash: F,
+    // Reads don't have mutable access, so the trace is kept in a RefCell.
+    trace: RefCell<Trace>,
+}
+
+impl<F: Flash> TraceFlash<F> {
+    pub fn new(flash: F) -> TraceFlash<F> {
+        TraceFlash {
+            flash: flash,
+            trace: RefCell::new(Trace::default()),
+        }
+    }
+
+    /// Remove the wrapper, returning the device, and the operations made on it.
+    pub fn into_parts(self) -> (F, Trace) {
+        (self.flash, self.trace.into_inner())
+    }
+}
+
+/// Operations are only recorded if they succeed.  Writes and erases interrupted by a loss of power
+/// aren't recorded.
+impl<F: Flash> Flash for TraceFlash<F> {
+    fn erase(&mut self, offset: usize, len: usize) -> Result<()> {
+        self.flash.erase(offset, len)?;
+        self.trace.borrow_mut().push(Op::Erase { offset: offset, len: len });
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, payload: &[u8]) -> Result<()> {
+        self.flash.write(offset, payload)?;
+        self.trace.borrow_mut().push(Op::Write { offset: offset, data: payload.to_vec() });
+        Ok(())
+    }
+
+    fn read(&self, offset: usize, data: &mut [u8]) -> Result<()> {
+        self.flash.read(offset, data)?;
+        self.trace.borrow_mut().push(Op::Read { offset: offset, data: data.to_vec() });
+        Ok(())
+    }
+
+    fn interrupted_write(&mut self, offset: usize, payload: &[u8]) -> Result<()> {
+        self.flash.interrupted_write(offset, payload)
+    }
+
+    fn interrupted_erase(&mut self, offset: usize, len: usize) -> Result<()> {
+        self.flash.interrupted_erase(offset, len)
+    }
+
+    fn sector_iter(&self) -> SectorIter {
+        self.flash.sector_iter()
+    }
+
+    fn device_size(&self) -> usize {
+        self.flash.device_size()
+    }
+
+    fn align(&self) -> usize {
+        self.flash.align()
+    }
+}
+
+fn etrace(message: String) -> ErrorKind {
+    ErrorKind::Trace(message)
+}
+
+// 64-bit FNV-1a.  The hash only has to tell operations apart when comparing traces.
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for &b in data {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn to_hex(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "-".to_string();
+    }
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text == "-" {
+        return Some(vec![]);
+    }
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0 .. text.len() / 2).map(|i| u8::from_str_radix(&text[2 * i .. 2 * i + 2], 16).ok()).collect()
+}
+
+fn parse_offset(text: &str) -> Option<usize> {
+    if text.starts_with("0x") {
+        usize::from_str_radix(&text[2..], 16).ok()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Op, Trace, TraceFlash};
+    use std::{env, fs, process};
+    use {Flash, SimFlash};
+
+    #[test]
+    fn test_trace() {
+        let path = env::temp_dir().join(format!("simflash-trace-{}.txt", process::id()));
+
+        let mut flash = TraceFlash::new(SimFlash::new(vec![4096usize; 4], 1));
+        let mut buf = [0; 4];
+        flash.erase(4096, 4096).unwrap();
+        flash.write(4096, &[1, 2, 3, 4]).unwrap();
+        flash.read(4094, &mut buf).unwrap();
+        assert!(flash.write(4096, &[5]).is_err());
+
+        let (orig, mut trace) = flash.into_parts();
+        assert_eq!(trace.ops, vec![
+            Op::Erase { offset: 4096, len: 4096 },
+            Op::Write { offset: 4096, data: vec![1, 2, 3, 4] },
+            Op::Read { offset: 4094, data: vec![0xff, 0xff, 1, 2] },
+        ]);
+
+        assert_eq!(trace.areas, vec![None; 3]);
+
+        trace.name_areas(&|off| if off >= 4096 { Some("second".to_string()) } else { None });
+        trace.save(&path).unwrap();
+        let loaded = Trace::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, trace);
+        assert_eq!(loaded.areas, vec![Some("second".to_string()), Some("second".to_string()), None]);
+
+        // Replaying against a fresh device gives the same result.
+        let mut copy = SimFlash::new(vec![4096usize; 4], 1);
+        loaded.replay(&mut copy).unwrap();
+        let mut a = vec![0; 4 * 4096];
+        let mut b = vec![0; 4 * 4096];
+        orig.read(0, &mut a).unwrap();
+        copy.read(0, &mut b).unwrap();
+        assert_eq!(a, b);
+
+        // But not against one with different contents.
+        let mut other = SimFlash::new(vec![4096usize; 4], 1);
+        other.write(4094, &[0]).unwrap();
+        assert!(loaded.replay(&mut other).is_err());
+    }
+}
@@ -16,6 +16,11 @@ impl Caps {
         let caps = unsafe { bootutil_get_caps() };
         (caps as u32) & (self as u32) != 0
     }
+
+    /// Does the bootloader verify image signatures at all.
+    pub fn signed() -> bool {
+        Caps::RSA2048.present() || Caps::EcdsaP224.present() || Caps::EcdsaP256.present()
+    }
 }
 
 extern "C" {
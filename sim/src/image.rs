@@ -0,0 +1,179 @@
+//! Image construction
+//!
+//! Normally, images are built with a header that matches their contents, and a valid TLV.  To test
+//! that the bootloader rejects bad images, the builder can also override each of the header fields
+//! independently of the contents, add extra TLV entries, and corrupt bytes after the image has been
+//! signed.
+
+use mcuboot_image::{ImageHeader, ImageVersion, IMAGE_HEADER_SIZE, IMAGE_MAGIC};
+use tlv::TlvGen;
+
+pub struct ImageBuilder {
+    tlv: TlvGen,
+    body: Vec<u8>,
+    magic: u32,
+    hdr_size: u16,
+    img_size: Option<u32>,
+    flags: Option<u32>,
+    key_id: Option<u8>,
+    tlv_size: Option<u16>,
+    ver: ImageVersion,
+    extra_tlvs: Vec<(u8, Vec<u8>)>,
+    zero_tlv: bool,
+    corrupt: Vec<usize>,
+}
+
+impl ImageBuilder {
+    /// Start building an image with the given body, signed according to the TLV generator.
+    pub fn new(tlv: TlvGen, body: Vec<u8>) -> ImageBuilder {
+        ImageBuilder {
+            tlv: tlv,
+            body: body,
+            magic: IMAGE_MAGIC,
+            hdr_size: IMAGE_HEADER_SIZE as u16,
+            img_size: None,
+            flags: None,
+            key_id: None,
+            tlv_size: None,
+            ver: Default::default(),
+            extra_tlvs: vec![],
+            zero_tlv: false,
+            corrupt: vec![],
+        }
+    }
+
+    /// Set the header magic.
+    pub fn magic(mut self, magic: u32) -> ImageBuilder {
+        self.magic = magic;
+        self
+    }
+
+    /// Set the header size.  Headers larger than the header structure are padded with zeros, and
+    /// the padding is covered by the hash.  Sizes smaller than the structure are recorded in the
+    /// header, but the full structure is still written.
+    pub fn hdr_size(mut self, hdr_size: u16) -> ImageBuilder {
+        self.hdr_size = hdr_size;
+        self
+    }
+
+    /// Override the image size in the header.  The body is unchanged.
+    pub fn img_size(mut self, img_size: u32) -> ImageBuilder {
+        self.img_size = Some(img_size);
+        self
+    }
+
+    /// Override the header flags, instead of using those for the signature type.
+    pub fn flags(mut self, flags: u32) -> ImageBuilder {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Override the key_id in the header, without changing the key used to sign.
+    pub fn key_id(mut self, key_id: u8) -> ImageBuilder {
+        self.key_id = Some(key_id);
+        self
+    }
+
+    /// Override the TLV size in the header.  The TLV entries written are unchanged.
+    pub fn tlv_size(mut self, tlv_size: u16) -> ImageBuilder {
+        self.tlv_size = Some(tlv_size);
+        self
+    }
+
+    /// Set the image version.
+    pub fn version(mut self, ver: ImageVersion) -> ImageBuilder {
+        self.ver = ver;
+        self
+    }
+
+    /// Append an additional TLV entry after the generated ones.  This can duplicate one of the
+    /// generated types.  Unless the TLV size is overridden, it is included in the header's size.
+    /// The entry's length field is 16 bits, so `data` can be at most 65535 bytes.
+    pub fn extra_tlv(mut self, kind: u8, data: &[u8]) -> ImageBuilder {
+        assert!(data.len() <= u16::max_value() as usize,
+                "extra TLV of type 0x{:02x} is {} bytes, which doesn't fit its 16 bit length",
+                kind, data.len());
+        self.extra_tlvs.push((kind, data.to_vec()));
+        self
+    }
+
+    /// Replace the generated TLV entries with zeros.  The header still describes them.
+    pub fn zero_tlv(mut self, zero: bool) -> ImageBuilder {
+        self.zero_tlv = zero;
+        self
+    }
+
+    /// Invert the byte at the given offset from the start of the image, after it has been signed.
+    /// The offset must lie within the built image, including its TLV entries.
+    pub fn corrupt(mut self, offset: usize) -> ImageBuilder {
+        self.corrupt.push(offset);
+        self
+    }
+
+    /// The offset of the first TLV entry from the start of the image.
+    pub fn tlv_offset(&self) -> usize {
+        self.header_len() + self.body.len()
+    }
+
+    // The number of bytes written for the header, including any padding.
+    fn header_len(&self) -> usize {
+        if (self.hdr_size as usize) < IMAGE_HEADER_SIZE {
+            IMAGE_HEADER_SIZE
+        } else {
+            self.hdr_size as usize
+        }
+    }
+
+    /// Build the image.
+    pub fn build(self) -> Vec<u8> {
+        let header_len = self.header_len();
+        let mut tlv = self.tlv;
+
+        let extra_size: usize = self.extra_tlvs.iter().map(|&(_, ref data)| 4 + data.len()).sum();
+        let tlv_size = self.tlv_size.unwrap_or_else(|| {
+            let size = tlv.get_size() as usize + extra_size;
+            assert!(size <= u16::max_value() as usize,
+                    "TLV entries total {} bytes, which doesn't fit the header's tlv_size", size);
+            size as u16
+        });
+        let header = ImageHeader {
+            magic: self.magic,
+            tlv_size: tlv_size,
+            key_id: self.key_id.unwrap_or(tlv.get_key_id()),
+            hdr_size: self.hdr_size,
+            img_size: self.img_size.unwrap_or(self.body.len() as u32),
+            flags: self.flags.unwrap_or(tlv.get_flags()),
+            ver: self.ver,
+        };
+
+        let mut image = header.to_bytes();
+        image.resize(header_len, 0);
+        image.extend_from_slice(&self.body);
+        tlv.add_bytes(&image);
+
+        let mut tlvs = tlv.make_tlv();
+        if self.zero_tlv {
+            for x in &mut tlvs {
+                *x = 0;
+            }
+        }
+        image.append(&mut tlvs);
+
+        for (kind, data) in self.extra_tlvs {
+            image.push(kind);
+            image.push(0);
+            image.push((data.len() & 0xFF) as u8);
+            image.push(((data.len() >> 8) & 0xFF) as u8);
+            image.extend_from_slice(&data);
+        }
+
+        for offset in self.corrupt {
+            assert!(offset < image.len(),
+                    "corrupt offset 0x{:x} is past the end of the 0x{:x} byte image",
+                    offset, image.len());
+            image[offset] ^= 0xff;
+        }
+
+        image
+    }
+}
@@ -0,0 +1,144 @@
+//! Signing keys
+//!
+//! Images are normally signed with the sample keys at the top of the mcuboot tree, which match the
+//! public keys in `boot/zephyr/keys.c`.  A different key can be loaded at runtime, either to check
+//! that images signed by an untrusted key are rejected, or to sign with a production key whose
+//! public half has been compiled into the bootloader.
+
+use pem;
+use ring::signature::{self, EcdsaKeyPair, RsaKeyPair};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+use untrusted;
+
+#[cfg(feature = "sig-ecdsa-p224")]
+use openssl::ec::EcKey;
+#[cfg(feature = "sig-ecdsa-p224")]
+use openssl::nid::Nid;
+#[cfg(feature = "sig-ecdsa-p224")]
+use openssl::pkey::Private;
+
+/// A private key that can be used to sign images.
+pub enum SigningKey {
+    Rsa(RsaKeyPair),
+    EcdsaP256(EcdsaKeyPair),
+    #[cfg(feature = "sig-ecdsa-p224")]
+    EcdsaP224(EcKey<Private>),
+}
+
+impl SigningKey {
+    /// Load a key from a file, which may be either PEM or DER encoded.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<SigningKey> {
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+
+        if data.starts_with(b"-----BEGIN") {
+            SigningKey::from_pem(&data)
+        } else {
+            SigningKey::from_der(&data)
+        }
+    }
+
+    /// Decode a PEM encoded key.  The tag determines how the contents are interpreted.
+    pub fn from_pem(data: &[u8]) -> io::Result<SigningKey> {
+        let block = pem::parse(data).map_err(|_| einval("Invalid PEM data"))?;
+        match &block.tag[..] {
+            "RSA PRIVATE KEY" => {
+                let key = RsaKeyPair::from_der(untrusted::Input::from(&block.contents))
+                    .map_err(|_| einval("Invalid RSA private key"))?;
+                Ok(SigningKey::Rsa(key))
+            }
+            "PRIVATE KEY" => SigningKey::from_pkcs8(&block.contents),
+            #[cfg(feature = "sig-ecdsa-p224")]
+            "EC PRIVATE KEY" => SigningKey::from_sec1(&block.contents),
+            #[cfg(not(feature = "sig-ecdsa-p224"))]
+            "EC PRIVATE KEY" => Err(einval("EC keys must be converted to PKCS#8")),
+            tag => Err(einval(format!("Unsupported PEM tag {:?}", tag))),
+        }
+    }
+
+    /// Decode a DER encoded key.  Since DER carries no tag, each of the supported encodings is
+    /// tried in turn.
+    pub fn from_der(data: &[u8]) -> io::Result<SigningKey> {
+        if let Ok(key) = RsaKeyPair::from_der(untrusted::Input::from(data)) {
+            return Ok(SigningKey::Rsa(key));
+        }
+        if let Ok(key) = SigningKey::from_pkcs8(data) {
+            return Ok(key);
+        }
+        SigningKey::from_other_der(data)
+    }
+
+    fn from_pkcs8(data: &[u8]) -> io::Result<SigningKey> {
+        let input = untrusted::Input::from(data);
+        if let Ok(key) = EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_ASN1_SIGNING, input) {
+            return Ok(SigningKey::EcdsaP256(key));
+        }
+        if let Ok(key) = RsaKeyPair::from_pkcs8(input) {
+            return Ok(SigningKey::Rsa(key));
+        }
+        Err(einval("Unsupported PKCS#8 private key"))
+    }
+
+    #[cfg(feature = "sig-ecdsa-p224")]
+    fn from_sec1(data: &[u8]) -> io::Result<SigningKey> {
+        let key = EcKey::private_key_from_der(data)
+            .map_err(|_| einval("Invalid EC private key"))?;
+        if key.group().curve_name() != Some(Nid::SECP224R1) {
+            return Err(einval("Only P-224 keys are supported in SEC1 form"));
+        }
+        Ok(SigningKey::EcdsaP224(key))
+    }
+
+    #[cfg(feature = "sig-ecdsa-p224")]
+    fn from_other_der(data: &[u8]) -> io::Result<SigningKey> {
+        SigningKey::from_sec1(data)
+    }
+
+    #[cfg(not(feature = "sig-ecdsa-p224"))]
+    fn from_other_der(_data: &[u8]) -> io::Result<SigningKey> {
+        Err(einval("Unrecognized DER private key"))
+    }
+}
+
+/// The sample private keys for the configured signature type, in key_id order.  These match the
+/// public keys that mcuboot-sys builds into the bootloader when `BOOTSIM_KEYS` isn't set.
+#[cfg(feature = "sig-rsa")]
+pub fn sample_keys() -> Vec<Arc<SigningKey>> {
+    vec![
+        sample_key(include_bytes!("../../root-rsa-2048.pem")),
+        sample_key(include_bytes!("../keys/second-rsa-2048.pem")),
+    ]
+}
+
+#[cfg(feature = "sig-ecdsa")]
+pub fn sample_keys() -> Vec<Arc<SigningKey>> {
+    vec![
+        sample_key(include_bytes!("../../root-ec-p256-pkcs8.pem")),
+        sample_key(include_bytes!("../keys/second-ec-p256-pkcs8.pem")),
+    ]
+}
+
+#[cfg(feature = "sig-ecdsa-p224")]
+pub fn sample_keys() -> Vec<Arc<SigningKey>> {
+    vec![
+        sample_key(include_bytes!("../keys/root-ec-p224.pem")),
+        sample_key(include_bytes!("../keys/second-ec-p224.pem")),
+    ]
+}
+
+#[cfg(not(any(feature = "sig-rsa", feature = "sig-ecdsa", feature = "sig-ecdsa-p224")))]
+pub fn sample_keys() -> Vec<Arc<SigningKey>> {
+    vec![]
+}
+
+#[allow(dead_code)]
+fn sample_key(pem: &[u8]) -> Arc<SigningKey> {
+    Arc::new(SigningKey::from_pem(pem).unwrap())
+}
+
+fn einval<T: Into<String>>(message: T) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
@@ -0,0 +1,220 @@
+//! Flash layouts read from a file
+//!
+//! The built in devices cover a few common flash layouts.  To test the layout of a particular
+//! board, the flash devices and partitions can instead be described in a TOML file, such as:
+//!
+//! ```toml
+//! [[flash]]
+//! id = 0
+//! sectors = [{ size = 4096, count = 128 }]
+//! align = 4
+//!
+//! [[area]]
+//! name = "image-0"
+//! base = 0x008000
+//! size = 0x034000
+//!
+//! [[area]]
+//! name = "image-1"
+//! base = 0x03c000
+//! size = 0x034000
+//!
+//! [[area]]
+//! name = "image-scratch"
+//! base = 0x070000
+//! size = 0x00d000
+//! ```
+//!
+//! Each flash device gives its sector sizes in order, its write alignment (default 1) and the
+//! value its bytes erase to (`erased-val`, default 0xff).  The bootloader only handles flash that
+//! erases to 0xff, so runs on any other value are expected to fail.  Each area is named as in the
+//! Zephyr device tree, and lies on the flash device with the given `flash` id (default 0).  An area
+//! marked `simple` is given to the bootloader as a single sector, however many sectors it covers.
+
+use mcuboot_sys::{c, AreaDesc, FlashId};
+use simflash::{SimFlash, SimFlashMap};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use toml;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    flash: Vec<FlashDesc>,
+    area: Vec<PartitionDesc>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct FlashDesc {
+    id: u8,
+    sectors: Vec<SectorRun>,
+    #[serde(default = "default_align")]
+    align: usize,
+    #[serde(default = "default_erased_val")]
+    erased_val: u8,
+}
+
+/// A run of sectors of the same size.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SectorRun {
+    size: usize,
+    #[serde(default = "default_count")]
+    count: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PartitionDesc {
+    name: String,
+    #[serde(default)]
+    flash: u8,
+    base: usize,
+    size: usize,
+    #[serde(default)]
+    simple: bool,
+}
+
+fn default_align() -> usize { 1 }
+fn default_erased_val() -> u8 { 0xff }
+fn default_count() -> usize { 1 }
+
+fn invalid<T: AsRef<str>>(message: T) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.as_ref())
+}
+
+impl Layout {
+    /// Read a layout from a TOML file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Layout> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        Layout::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Layout> {
+        toml::from_str(text).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Build the flash devices, and the description of the areas on them.
+    pub fn build(&self) -> io::Result<(SimFlashMap, AreaDesc)> {
+        let mut flashmap = SimFlashMap::new();
+        for dev in &self.flash {
+            if dev.align == 0 || dev.align & (dev.align - 1) != 0 {
+                return Err(invalid(format!("flash {}: alignment {} is not a power of two",
+                                           dev.id, dev.align)));
+            }
+
+            let mut sectors = vec![];
+            for run in &dev.sectors {
+                sectors.extend(vec![run.size; run.count]);
+            }
+            if dev.erased_val != c::boot_erased_val() {
+                warn!("flash {}: the bootloader expects erased flash to read as 0x{:02x}, not \
+                       0x{:02x}, so runs on it are expected to fail",
+                      dev.id, c::boot_erased_val(), dev.erased_val);
+            }
+            let flash = SimFlash::new(sectors, dev.align).with_erased_val(dev.erased_val);
+            if flashmap.insert(dev.id, flash).is_some() {
+                return Err(invalid(format!("flash {} is described twice", dev.id)));
+            }
+        }
+
+        let mut areas = vec![];
+        for part in &self.area {
+            let id = match FlashId::from_name(&part.name) {
+                Some(id) => id,
+                None => return Err(invalid(format!("unknown area \"{}\"", part.name))),
+            };
+            if !flashmap.contains_key(&part.flash) {
+                return Err(invalid(format!("area \"{}\" is on flash {}, which isn't described",
+                                           part.name, part.flash)));
+            }
+            if areas.iter().any(|&(other, _)| other == id) {
+                return Err(invalid(format!("area \"{}\" is described twice", part.name)));
+            }
+            areas.push((id, part));
+        }
+
+        // The areas have to be given to the bootloader in order.
+        areas.sort_by_key(|&(id, _)| id as u8);
+
+        let mut areadesc = AreaDesc::new(&flashmap);
+        for (id, part) in areas {
+            if part.simple {
+                areadesc.add_simple_image(part.base, part.size, id, part.flash);
+            } else if let Err(e) = areadesc.try_add_image(part.base, part.size, id, part.flash) {
+                return Err(invalid(format!("area \"{}\": {}", part.name, e)));
+            }
+        }
+
+        Ok((flashmap, areadesc))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Layout;
+    use mcuboot_sys::FlashId;
+    use simflash::Flash;
+
+    #[test]
+    fn test_layout() {
+        let layout = Layout::parse(r#"
+            [[flash]]
+            id = 0
+            sectors = [{ size = 16384, count = 4 }, { size = 65536 }, { size = 131072, count = 3 }]
+
+            [[flash]]
+            id = 1
+            sectors = [{ size = 4096, count = 256 }]
+            align = 8
+            erased-val = 0
+
+            [[area]]
+            name = "image-scratch"
+            flash = 1
+            base = 0x20000
+            size = 0x20000
+
+            [[area]]
+            name = "image-0"
+            base = 0x20000
+            size = 0x20000
+
+            [[area]]
+            name = "image-1"
+            flash = 1
+            base = 0
+            size = 0x20000
+            simple = true
+        "#).unwrap();
+
+        let (flashmap, areadesc) = layout.build().unwrap();
+        assert_eq!(flashmap[&0].sector_iter().count(), 8);
+        assert_eq!(flashmap[&1].erased_val(), 0);
+        assert_eq!(areadesc.find(FlashId::Image0), (0x20000, 0x20000, 0));
+        assert_eq!(areadesc.find(FlashId::Image1), (0, 0x20000, 1));
+        assert_eq!(areadesc.align(FlashId::ImageScratch), 8);
+    }
+
+    #[test]
+    fn test_bad_layout() {
+        let flash = "[[flash]]\nid = 0\nsectors = [{ size = 4096, count = 128 }]\n";
+
+        assert!(Layout::parse("[[flash]]\nid = 0\n").is_err());
+        assert!(Layout::parse(&format!("{}[[area]]\nname = \"image-0\"\nbase = 0\nsize = 4096\n\
+                                        colour = \"blue\"\n", flash)).is_err());
+
+        let unknown = format!("{}[[area]]\nname = \"image-7\"\nbase = 0\nsize = 4096\n", flash);
+        assert!(Layout::parse(&unknown).unwrap().build().is_err());
+
+        let no_flash = format!("{}[[area]]\nname = \"image-0\"\nflash = 1\nbase = 0\nsize = 4096\n",
+                               flash);
+        assert!(Layout::parse(&no_flash).unwrap().build().is_err());
+
+        let bad_align = "area = []\n[[flash]]\nid = 0\nsectors = [{ size = 4096 }]\nalign = 3\n";
+        assert!(Layout::parse(bad_align).unwrap().build().is_err());
+    }
+}
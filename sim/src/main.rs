@@ -4,28 +4,46 @@ extern crate env_logger;
 #[macro_use] extern crate bitflags;
 extern crate docopt;
 extern crate libc;
+extern crate num_cpus;
+#[cfg(feature = "sig-ecdsa-p224")] extern crate openssl;
 extern crate pem;
 extern crate rand;
 #[macro_use] extern crate serde_derive;
 extern crate serde;
 extern crate simflash;
+extern crate toml;
 extern crate untrusted;
 extern crate mcuboot_sys;
+extern crate mcuboot_image;
 
 use docopt::Docopt;
 use rand::{Rng, SeedableRng, XorShiftRng};
 use rand::distributions::{IndependentSample, Range};
+use std::collections::HashMap;
 use std::fmt;
-use std::mem;
+use std::fs::File;
+use std::io::{self, Read};
+use std::panic::{self, AssertUnwindSafe};
 use std::process;
-use std::slice;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
 mod caps;
+mod image;
+mod keys;
+mod layout;
 mod tlv;
 
-use simflash::{Flash, SimFlash};
-use mcuboot_sys::{c, AreaDesc, FlashId};
+use simflash::{Fault, FaultFlash, Flash, PowerLoss, SimFlash, SimFlashMap, Timing, TraceFlash,
+               WearOut, WritePolicy};
+use mcuboot_sys::{c, AreaDesc, FlashId, LayoutError};
+use mcuboot_image::{Image, ImageHeader, ImageVersion, IMAGE_F_NON_BOOTABLE, IMAGE_F_SHA256,
+                    IMAGE_HEADER_SIZE, IMAGE_TLV_SHA256};
 use caps::Caps;
+use image::ImageBuilder;
+use keys::SigningKey;
+use layout::Layout;
 use tlv::TlvGen;
 
 const USAGE: &'static str = "
@@ -33,16 +51,46 @@ Mcuboot simulator
 
 Usage:
   bootsim sizes
-  bootsim run --device TYPE [--align SIZE]
-  bootsim runall
+  bootsim run --device TYPE [--align SIZE] [--dts FILE [--flash NODE...] | --bsp FILE]
+              [--key FILE...] [--slot0 FILE --slot1 FILE] [--torn] [--trace FILE]
+  bootsim run --layout FILE [--key FILE...] [--slot0 FILE --slot1 FILE]
+              [--torn] [--trace FILE]
+  bootsim runall [--key FILE...] [--torn]
+  bootsim wear [--cycles N] [--key FILE...]
+  bootsim timing [--key FILE...]
   bootsim (--help | --version)
 
 Options:
   -h, --help         Show this message
   --version          Version
   --device TYPE      MCU to simulate
-                     Valid values: stm32f4, k64f
+                     Valid values: stm32f4, k64f, k64fbig, k64freordered,
+                     k64fnoscratch, k64fclearbits, k64frewrite,
+                     k64ferasedzero, nrf52840, nrf52840gaps,
+                     nrf52840noscratch, nrf52840spiflash
   --align SIZE       Flash write alignment
+  --dts FILE         Use the partitions from this Zephyr device tree
+                     source, instead of those of the device
+  --flash NODE       The flash node in the device tree of each of the
+                     device's flash devices, in order.  Repeat for each
+                     flash device [default: flash0]
+  --bsp FILE         Use the flash areas from this Mynewt bsp.yml,
+                     instead of those of the device
+  --layout FILE      Simulate the flash devices and partitions described
+                     in this TOML file, instead of a built in device
+  --key FILE         Sign images with this private key (PEM or DER)
+                     instead of the sample keys.  Repeat to give the
+                     keys for each key_id, in order
+  --slot0 FILE       Install this signed image, as produced by
+                     imgtool.py sign, as the primary image
+  --slot1 FILE       Install this signed image as the upgrade
+  --torn             When power is lost during a write or erase, a
+                     random part of it takes effect, and the bits
+                     around where it stopped are left unstable
+  --trace FILE       Save the flash operations made during an upgrade
+                     to FILE
+  --cycles N         Number of upgrades to run on each device
+                     [default: 100]
 ";
 
 #[derive(Debug, Deserialize)]
@@ -51,28 +99,65 @@ struct Args {
     flag_version: bool,
     flag_device: Option<DeviceName>,
     flag_align: Option<AlignArg>,
+    flag_dts: Option<String>,
+    flag_bsp: Option<String>,
+    flag_flash: Vec<String>,
+    flag_layout: Option<String>,
+    flag_key: Vec<String>,
+    flag_slot0: Option<String>,
+    flag_slot1: Option<String>,
+    flag_torn: bool,
+    flag_trace: Option<String>,
+    flag_cycles: usize,
     cmd_sizes: bool,
     cmd_run: bool,
     cmd_runall: bool,
+    cmd_wear: bool,
+    cmd_timing: bool,
 }
 
 #[derive(Copy, Clone, Debug, Deserialize)]
-enum DeviceName { Stm32f4, K64f, K64fBig, Nrf52840 }
+enum DeviceName { Stm32f4, K64f, K64fBig, K64fReordered, K64fNoScratch, K64fClearBits,
+                  K64fRewrite, K64fErasedZero, Nrf52840, Nrf52840Gaps, Nrf52840NoScratch,
+                  Nrf52840SpiFlash }
 
 static ALL_DEVICES: &'static [DeviceName] = &[
     DeviceName::Stm32f4,
     DeviceName::K64f,
     DeviceName::K64fBig,
+    DeviceName::K64fReordered,
+    DeviceName::K64fNoScratch,
+    DeviceName::K64fClearBits,
+    DeviceName::K64fRewrite,
+    DeviceName::K64fErasedZero,
     DeviceName::Nrf52840,
+    DeviceName::Nrf52840Gaps,
+    DeviceName::Nrf52840NoScratch,
+    DeviceName::Nrf52840SpiFlash,
 ];
 
+/// The write alignments to test each device with, of those the bootloader was built for.
+static ALL_ALIGNS: &'static [u8] = &[1, 2, 4, 8, 16, 32];
+
+fn all_aligns() -> Vec<u8> {
+    ALL_ALIGNS.iter().cloned().filter(|&align| align as usize <= c::boot_max_align()).collect()
+}
+
 impl fmt::Display for DeviceName {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let name = match *self {
             DeviceName::Stm32f4 => "stm32f4",
             DeviceName::K64f => "k64f",
             DeviceName::K64fBig => "k64fbig",
+            DeviceName::K64fReordered => "k64freordered",
+            DeviceName::K64fNoScratch => "k64fnoscratch",
+            DeviceName::K64fClearBits => "k64fclearbits",
+            DeviceName::K64fRewrite => "k64frewrite",
+            DeviceName::K64fErasedZero => "k64ferasedzero",
             DeviceName::Nrf52840 => "nrf52840",
+            DeviceName::Nrf52840Gaps => "nrf52840gaps",
+            DeviceName::Nrf52840NoScratch => "nrf52840noscratch",
+            DeviceName::Nrf52840SpiFlash => "nrf52840spiflash",
         };
         f.write_str(name)
     }
@@ -87,14 +172,14 @@ impl<'de> serde::de::Visitor<'de> for AlignArgVisitor {
     type Value = AlignArg;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("1, 2, 4 or 8")
+        formatter.write_str("1, 2, 4, 8, 16 or 32")
     }
 
     fn visit_u8<E>(self, n: u8) -> Result<Self::Value, E>
         where E: serde::de::Error
     {
         Ok(match n {
-            1 | 2 | 4 | 8 => AlignArg(n),
+            1 | 2 | 4 | 8 | 16 | 32 => AlignArg(n),
             n => {
                 let err = format!("Could not deserialize '{}' as alignment", n);
                 return Err(E::custom(err));
@@ -124,109 +209,311 @@ fn main() {
         return;
     }
 
-    let mut status = RunStatus::new();
-    if args.cmd_run {
+    let keys = if args.flag_key.is_empty() {
+        keys::sample_keys()
+    } else {
+        args.flag_key.iter().map(|path| {
+            match SigningKey::load(path) {
+                Ok(key) => Arc::new(key),
+                Err(e) => {
+                    error!("Unable to load key {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }).collect()
+    };
 
-        let align = args.flag_align.map(|x| x.0).unwrap_or(1);
+    let mut status = RunStatus::new(keys);
+    status.torn = args.flag_torn;
+    status.trace = args.flag_trace;
+    if let (Some(slot0), Some(slot1)) = (args.flag_slot0, args.flag_slot1) {
+        status.user_images = Some(UserImages {
+            slot0: load_user_image(&slot0),
+            slot1: load_user_image(&slot1),
+        });
+    }
 
+    if args.cmd_run {
+        if let Some(ref path) = args.flag_layout {
+            status.run_layout(path);
+        } else {
+            let align = args.flag_align.map(|x| x.0).unwrap_or(1);
+            let device = match args.flag_device {
+                None => panic!("Missing mandatory device argument"),
+                Some(dev) => dev,
+            };
+
+            if let Some(ref path) = args.flag_dts {
+                let flashes: Vec<&str> = args.flag_flash.iter().map(|s| s.as_str()).collect();
+                status.run_board(device, align, path, &|flashmap, text| {
+                    AreaDesc::from_dts(flashmap, text, &flashes)
+                });
+            } else if let Some(ref path) = args.flag_bsp {
+                status.run_board(device, align, path, &AreaDesc::from_bsp_yml);
+            } else {
+                status.run_single(device, align);
+            }
+        }
+    }
 
-        let device = match args.flag_device {
-            None => panic!("Missing mandatory device argument"),
-            Some(dev) => dev,
-        };
+    if args.cmd_wear || args.cmd_timing {
+        // These use the same devices as runall, at a single alignment, and have nothing to say
+        // about devices that the bootloader can't run on.
+        let align = 1;
+        for &dev in ALL_DEVICES {
+            let (flashmap, areadesc) = make_device(dev, align);
+            if !status.usable(dev, align, &areadesc) {
+                continue;
+            }
+            if !bootloader_handles(&flashmap) {
+                warn!("Skipping device {}: the bootloader can't run on its flash", dev);
+                continue;
+            }
 
-        status.run_single(device, align);
+            if args.cmd_wear {
+                status.show_wear(dev, flashmap, &areadesc, args.flag_cycles);
+            } else {
+                status.show_timing(dev, flashmap, &areadesc);
+            }
+        }
+        if status.failures.load(Ordering::SeqCst) > 0 {
+            process::exit(1);
+        }
+        return;
     }
 
+    let status = Arc::new(status);
+
     if args.cmd_runall {
+        // The devices and alignments are shared out among a pool of worker threads, one for each
+        // CPU.
+        let mut jobs = vec![];
         for &dev in ALL_DEVICES {
-            for &align in &[1, 2, 4, 8] {
-                status.run_single(dev, align);
+            for align in all_aligns() {
+                let (flashmap, areadesc) = make_device(dev, align);
+                if !status.usable(dev, align, &areadesc) {
+                    continue;
+                }
+
+                let expect_failure = !bootloader_handles(&flashmap);
+                jobs.push((dev, align, expect_failure));
             }
         }
+        // Take the jobs in the order they were listed.
+        jobs.reverse();
+        let jobs = Arc::new(Mutex::new(jobs));
+
+        let workers: Vec<_> = (0..num_cpus::get()).map(|_| {
+            let status = status.clone();
+            let jobs = jobs.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = jobs.lock().unwrap().pop();
+                    let (dev, align, expect_failure) = match job {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        status.run_single(dev, align)
+                    }));
+                    if result.is_err() {
+                        // The simulation panicked.
+                        status.record(true, expect_failure);
+                    }
+                }
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
     }
 
-    if status.failures > 0 {
-        error!("{} Tests ran with {} failures", status.failures + status.passes, status.failures);
-        process::exit(1);
-    } else {
-        error!("{} Tests ran successfully", status.passes);
-        process::exit(0);
-    }
+    status.exit();
 }
 
 struct RunStatus {
-    failures: usize,
-    passes: usize,
+    // Runs can be done in parallel, so these are updated atomically.
+    failures: AtomicUsize,
+    passes: AtomicUsize,
+    expected_failures: AtomicUsize,
+    // Devices and alignments that weren't run, because their layout can't be used.
+    skipped: AtomicUsize,
+    // Keys to sign the images with, indexed by key_id.  These must match the public keys that the
+    // bootloader was built with.
+    keys: Vec<Arc<SigningKey>>,
+    // Images to test with in place of the synthetic ones.
+    user_images: Option<UserImages>,
+    // Whether interrupted flash operations partly take effect.
+    torn: bool,
+    // Where to save a trace of the flash operations in an upgrade.
+    trace: Option<String>,
+}
+
+/// A pair of signed images, read from files.
+struct UserImages {
+    slot0: Vec<u8>,
+    slot1: Vec<u8>,
 }
 
 impl RunStatus {
-    fn new() -> RunStatus {
+    fn new(keys: Vec<Arc<SigningKey>>) -> RunStatus {
         RunStatus {
-            failures: 0,
-            passes: 0,
+            failures: AtomicUsize::new(0),
+            passes: AtomicUsize::new(0),
+            expected_failures: AtomicUsize::new(0),
+            skipped: AtomicUsize::new(0),
+            keys: keys,
+            user_images: None,
+            torn: false,
+            trace: None,
         }
     }
 
-    fn run_single(&mut self, device: DeviceName, align: u8) {
+    /// Build the TLV generator for the configured signature type, signing with the first key.
+    fn make_tlv(&self) -> TlvGen {
+        self.make_tlv_with_key(0)
+    }
+
+    /// Build the TLV generator for the configured signature type, signing with the given entry in
+    /// the key table, and setting the key_id to match.
+    fn make_tlv_with_key(&self, key_id: usize) -> TlvGen {
+        let mut tlv = make_tlv();
+        if let Some(key) = self.keys.get(key_id) {
+            tlv.set_key(key.clone());
+        }
+        tlv.set_key_id(key_id as u8);
+        tlv
+    }
+
+    /// Report the results of the runs, and exit.
+    fn exit(&self) -> ! {
+        let failures = self.failures.load(Ordering::SeqCst);
+        let passes = self.passes.load(Ordering::SeqCst);
+        let expected = self.expected_failures.load(Ordering::SeqCst);
+        let skipped = self.skipped.load(Ordering::SeqCst);
+        if expected > 0 {
+            warn!("{} Tests failed as expected", expected);
+        }
+        if skipped > 0 {
+            warn!("{} Devices and alignments skipped", skipped);
+        }
+        if failures > 0 {
+            error!("{} Tests ran with {} failures", failures + passes + expected, failures);
+            process::exit(1);
+        } else {
+            error!("{} Tests ran successfully", passes + expected);
+            process::exit(0);
+        }
+    }
+
+    /// Check whether a device's layout can be used at the given alignment, for the commands that
+    /// run on every device.  Not every device can be used at every alignment, and those are
+    /// skipped, but any other problem with the layout is a failure.
+    fn usable(&self, device: DeviceName, align: u8, areadesc: &AreaDesc) -> bool {
+        let errors = match areadesc.validate() {
+            Ok(()) => return true,
+            Err(errors) => errors,
+        };
+
+        if errors.iter().all(expected_layout_error) {
+            warn!("Skipping device {} with alignment {}: {}", device, align, errors[0]);
+            self.skipped.fetch_add(1, Ordering::SeqCst);
+        } else {
+            for e in &errors {
+                error!("Invalid layout for device {} with alignment {}: {}", device, align, e);
+            }
+            self.failures.fetch_add(1, Ordering::SeqCst);
+        }
+        false
+    }
+
+    fn run_single(&self, device: DeviceName, align: u8) {
         warn!("Running on device {} with alignment {}", device, align);
 
-        let (mut flash, areadesc) = match device {
-            DeviceName::Stm32f4 => {
-                // STM style flash.  Large sectors, with a large scratch area.
-                let flash = SimFlash::new(vec![16 * 1024, 16 * 1024, 16 * 1024, 16 * 1024,
-                                          64 * 1024,
-                                          128 * 1024, 128 * 1024, 128 * 1024],
-                                          align as usize);
-                let mut areadesc = AreaDesc::new(&flash);
-                areadesc.add_image(0x020000, 0x020000, FlashId::Image0);
-                areadesc.add_image(0x040000, 0x020000, FlashId::Image1);
-                areadesc.add_image(0x060000, 0x020000, FlashId::ImageScratch);
-                (flash, areadesc)
-            }
-            DeviceName::K64f => {
-                // NXP style flash.  Small sectors, one small sector for scratch.
-                let flash = SimFlash::new(vec![4096; 128], align as usize);
-
-                let mut areadesc = AreaDesc::new(&flash);
-                areadesc.add_image(0x020000, 0x020000, FlashId::Image0);
-                areadesc.add_image(0x040000, 0x020000, FlashId::Image1);
-                areadesc.add_image(0x060000, 0x001000, FlashId::ImageScratch);
-                (flash, areadesc)
-            }
-            DeviceName::K64fBig => {
-                // Simulating an STM style flash on top of an NXP style flash.  Underlying flash device
-                // uses small sectors, but we tell the bootloader they are large.
-                let flash = SimFlash::new(vec![4096; 128], align as usize);
-
-                let mut areadesc = AreaDesc::new(&flash);
-                areadesc.add_simple_image(0x020000, 0x020000, FlashId::Image0);
-                areadesc.add_simple_image(0x040000, 0x020000, FlashId::Image1);
-                areadesc.add_simple_image(0x060000, 0x020000, FlashId::ImageScratch);
-                (flash, areadesc)
-            }
-            DeviceName::Nrf52840 => {
-                // Simulating the flash on the nrf52840 with partitions set up so that the scratch size
-                // does not divide into the image size.
-                let flash = SimFlash::new(vec![4096; 128], align as usize);
-
-                let mut areadesc = AreaDesc::new(&flash);
-                areadesc.add_image(0x008000, 0x034000, FlashId::Image0);
-                areadesc.add_image(0x03c000, 0x034000, FlashId::Image1);
-                areadesc.add_image(0x070000, 0x00d000, FlashId::ImageScratch);
-                (flash, areadesc)
+        let (flashmap, areadesc) = make_device(device, align);
+        self.run_on(flashmap, areadesc);
+    }
+
+    /// Run the tests on the flash of a device, using the partitions from a board definition,
+    /// which `import` reads.
+    fn run_board(&self, device: DeviceName, align: u8, path: &str,
+                 import: &Fn(&SimFlashMap, &str) -> io::Result<AreaDesc>) {
+        warn!("Running on device {} with alignment {}, partitions from {}", device, align, path);
+
+        let (flashmap, _) = make_device(device, align);
+        let mut text = String::new();
+        let areadesc = File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .and_then(|_| import(&flashmap, &text));
+        match areadesc {
+            Ok(areadesc) => self.run_on(flashmap, areadesc),
+            Err(e) => {
+                error!("Unable to use partitions from {}: {}", path, e);
+                self.failures.fetch_add(1, Ordering::SeqCst);
             }
-        };
+        }
+    }
+
+    /// Run the tests on a layout read from a file.
+    fn run_layout(&self, path: &str) {
+        warn!("Running on layout {}", path);
 
-        let (slot0_base, slot0_len) = areadesc.find(FlashId::Image0);
-        let (slot1_base, slot1_len) = areadesc.find(FlashId::Image1);
-        let (scratch_base, _) = areadesc.find(FlashId::ImageScratch);
+        match Layout::load(path).and_then(|layout| layout.build()) {
+            Ok((flashmap, areadesc)) => self.run_on(flashmap, areadesc),
+            Err(e) => {
+                error!("Unable to use layout {}: {}", path, e);
+                self.failures.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
 
-        // Code below assumes that the slots are consecutive.
-        assert_eq!(slot1_base, slot0_base + slot0_len);
-        assert_eq!(scratch_base, slot1_base + slot1_len);
+    /// Run the tests on the given flash devices and partitions.
+    fn run_on(&self, flashmap: SimFlashMap, areadesc: AreaDesc) {
+        if let Err(errors) = areadesc.validate() {
+            for e in &errors {
+                error!("Invalid layout: {}", e);
+            }
+            self.failures.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
 
-        let offset_from_end = c::boot_magic_sz() + c::boot_max_align() * 2;
+        let expect_failure = !bootloader_handles(&flashmap);
+        let failed = self.run_tests(flashmap, areadesc);
+        self.record(failed, expect_failure);
+    }
+
+    /// Count the result of a run.  Runs on flash that the bootloader can't handle are expected to
+    /// fail, and passing is an error, so that they get noticed once it can.
+    fn record(&self, failed: bool, expect_failure: bool) {
+        match (failed, expect_failure) {
+            (false, false) => {
+                self.passes.fetch_add(1, Ordering::SeqCst);
+            }
+            (true, false) => {
+                self.failures.fetch_add(1, Ordering::SeqCst);
+            }
+            (true, true) => {
+                warn!("Failed, as expected");
+                self.expected_failures.fetch_add(1, Ordering::SeqCst);
+            }
+            (false, true) => {
+                error!("Passed, but was expected to fail");
+                self.failures.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Run the scenarios, returning true if any of them failed.
+    fn run_tests(&self, mut flashmap: SimFlashMap, areadesc: AreaDesc) -> bool {
+        if self.torn {
+            flashmap = flashmap.into_iter()
+                .map(|(id, flash)| {
+                    (id, flash.with_power_loss(PowerLoss::Random).with_unstable_bits(true))
+                })
+                .collect();
+        }
+
+        let (slot0, slot1) = make_slots(&areadesc);
 
         // println!("Areas: {:#?}", areadesc.get_c());
 
@@ -234,68 +521,658 @@ impl RunStatus {
         // TODO: This must be a multiple of flash alignment, add support for an image that is smaller,
         // and just gets padded.
 
-        // Create original and upgrade images
-        let slot0 = SlotInfo {
-            base_off: slot0_base as usize,
-            trailer_off: slot1_base - offset_from_end,
+        let mut failed = false;
+
+        let images = match self.user_images {
+            Some(ref user) => {
+                // Real images say nothing about how the bootloader handles synthetic ones, so only
+                // the upgrade scenarios are run on them.
+                let primary = install_user_image(&mut flashmap, &areadesc, FlashId::Image0,
+                                                 &user.slot0);
+                let upgrade = install_user_image(&mut flashmap, &areadesc, FlashId::Image1,
+                                                 &user.slot1);
+                match (primary, upgrade) {
+                    (Some(primary), Some(upgrade)) => Images {
+                        slot0: &slot0,
+                        slot1: &slot1,
+                        primary: primary,
+                        upgrade: upgrade,
+                    },
+                    _ => return true,
+                }
+            }
+            None => {
+                failed |= self.run_rejection_tests(&flashmap, &areadesc, &slot0, &slot1);
+
+                Images {
+                    slot0: &slot0,
+                    slot1: &slot1,
+                    primary: install_image(&mut flashmap, &slot0, 32784, self.make_tlv(), false),
+                    upgrade: install_image(&mut flashmap, &slot1, 41928, self.make_tlv(), false),
+                }
+            }
         };
 
-        let slot1 = SlotInfo {
-            base_off: slot1_base as usize,
-            trailer_off: scratch_base - offset_from_end,
+        failed |= run_norevert_newimage(&flashmap, &areadesc, &images);
+
+        mark_upgrade(&mut flashmap, &images.slot1);
+
+        // upgrades without fails, counts number of flash operations
+        let total_count = match run_basic_upgrade(&flashmap, &areadesc, &images) {
+            Ok(v)  => v,
+            Err(_) => return true,
         };
 
-        // Set an alignment, and position the magic value.
-        c::set_sim_flash_align(align);
+        if let Some(ref path) = self.trace {
+            failed |= save_upgrade_trace(&flashmap, &areadesc, &images, path);
+        }
 
+        failed |= run_basic_revert(&flashmap, &areadesc, &images);
+        failed |= run_revert_with_fails(&flashmap, &areadesc, &images, total_count);
+        failed |= run_perm_with_fails(&flashmap, &areadesc, &images, total_count);
+        failed |= run_perm_with_random_fails(&flashmap, &areadesc, &images,
+                                             total_count, 5);
+        failed |= run_with_flash_faults(&flashmap, &areadesc, &images, 5);
+        failed |= run_with_bit_flips(&flashmap, &areadesc, &images, 5);
+        failed |= run_norevert(&flashmap, &areadesc, &images);
+
+        //show_flash(&flash);
+
+        failed
+    }
+}
+
+impl RunStatus {
+    /// Run back to back upgrades on a device, each followed by a revert when the bootloader
+    /// supports it, and show how many times the sectors in each area were erased and written.  The
+    /// sectors are rated for `ENDURANCE` erases, and the upgrades stop early if any wear out.
+    fn show_wear(&self, device: DeviceName, flashmap: SimFlashMap, areadesc: &AreaDesc,
+                 cycles: usize) {
+        let mut flashmap: SimFlashMap = flashmap.into_iter()
+            .map(|(id, flash)| (id, flash.with_endurance(ENDURANCE, WearOut::Fail)))
+            .collect();
+        let (slot0, slot1) = make_slots(areadesc);
+
+        install_image(&mut flashmap, &slot0, 32784, self.make_tlv(), false);
+        install_image(&mut flashmap, &slot1, 41928, self.make_tlv(), false);
+        let erases0: HashMap<u8, Vec<u32>> = flashmap.iter()
+            .map(|(&id, flash)| (id, flash.erase_counts().to_vec()))
+            .collect();
+        let writes0: HashMap<u8, Vec<u32>> = flashmap.iter()
+            .map(|(&id, flash)| (id, flash.write_counts().to_vec()))
+            .collect();
+
+        c::set_flash_counter(0);
+        let mut done = 0;
+        'cycles: for cycle in 0 .. cycles {
+            if !Caps::SwapUpgrade.present() && cycle > 0 {
+                // Overwrite only erases the upgrade, so a new one has to be received, just as on a
+                // real device.
+                let (base, len, dev_id) = areadesc.find(FlashId::Image1);
+                if flashmap.get_mut(&dev_id).unwrap().erase(base, len).is_err() {
+                    println!("{}: slot 1 worn out before upgrade {}", device, cycle + 1);
+                    break 'cycles;
+                }
+                install_image(&mut flashmap, &slot1, 41928, self.make_tlv(), false);
+            }
+
+            mark_upgrade(&mut flashmap, &slot1);
+            let boots = if Caps::SwapUpgrade.present() { 2 } else { 1 };
+            for _ in 0 .. boots {
+                if c::boot_go(&mut flashmap, areadesc) != 0 {
+                    if worn_out(&flashmap) {
+                        println!("{}: sectors worn out during upgrade {}", device, cycle + 1);
+                        break 'cycles;
+                    }
+                    error!("{}: boot failed on cycle {}", device, cycle);
+                    self.failures.fetch_add(1, Ordering::SeqCst);
+                    return;
+                }
+            }
+            done += 1;
+        }
+
+        println!("{}: {} upgrades, endurance {} erases", device, done, ENDURANCE);
+        for &(id, name) in &[(FlashId::Image0, "slot0"), (FlashId::Image1, "slot1"),
+                             (FlashId::ImageScratch, "scratch")] {
+            // Overwrite only layouts have no scratch area.
+            let (base, len, dev_id) = match areadesc.try_find(id) {
+                Some(area) => area,
+                None => continue,
+            };
+            let flash = &flashmap[&dev_id];
+            let sectors: Vec<usize> = flash.sector_iter()
+                .filter(|s| s.base >= base && s.base < base + len)
+                .map(|s| s.num)
+                .collect();
+            let erases: Vec<u32> = sectors.iter()
+                .map(|&n| flash.erase_counts()[n] - erases0[&dev_id][n]).collect();
+            let writes: Vec<u32> = sectors.iter()
+                .map(|&n| flash.write_counts()[n] - writes0[&dev_id][n]).collect();
+            println!("    {:8} {:3} sectors, erases max {:6} total {:8}, writes max {:6} total {:8}",
+                     name, sectors.len(),
+                     erases.iter().max().unwrap_or(&0), erases.iter().sum::<u32>(),
+                     writes.iter().max().unwrap_or(&0), writes.iter().sum::<u32>());
+        }
+    }
+
+    /// Estimate how long the bootloader spends in flash operations on a device: for an upgrade, for
+    /// the revert that follows it, and for the slowest boot after power is lost during an upgrade.
+    fn show_timing(&self, device: DeviceName, mut flashmap: SimFlashMap, areadesc: &AreaDesc) {
+        let (slot0, slot1) = make_slots(areadesc);
+
+        install_image(&mut flashmap, &slot0, 32784, self.make_tlv(), false);
+        install_image(&mut flashmap, &slot1, 41928, self.make_tlv(), false);
+        mark_upgrade(&mut flashmap, &slot1);
+
+        let mut fl = flashmap.clone();
+        reset_clocks(&fl);
+        c::set_flash_counter(0);
+        if c::boot_go(&mut fl, areadesc) != 0 {
+            error!("{}: upgrade failed", device);
+            self.failures.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+        let upgrade_us = elapsed_us(&fl);
+        let total_ops = -c::get_flash_counter();
+
+        reset_clocks(&fl);
+        c::set_flash_counter(0);
+        if c::boot_go(&mut fl, areadesc) != 0 {
+            error!("{}: revert failed", device);
+            self.failures.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+        let revert_us = elapsed_us(&fl);
+
+        let mut worst_us = 0;
+        for stop in 1 .. total_ops {
+            let mut fl = flashmap.clone();
+            c::set_flash_counter(stop);
+            match c::boot_go(&mut fl, areadesc) {
+                -0x13579 => (),
+                x => {
+                    error!("{}: boot to be interrupted at {} returned {}", device, stop, x);
+                    self.failures.fetch_add(1, Ordering::SeqCst);
+                    return;
+                }
+            }
+
+            reset_clocks(&fl);
+            c::set_flash_counter(0);
+            if c::boot_go(&mut fl, areadesc) != 0 {
+                error!("{}: boot after power loss at {} failed", device, stop);
+                self.failures.fetch_add(1, Ordering::SeqCst);
+                return;
+            }
+            if elapsed_us(&fl) > worst_us {
+                worst_us = elapsed_us(&fl);
+            }
+        }
+
+        println!("{}: upgrade {:.1} ms, revert {:.1} ms, worst after power loss {:.1} ms",
+                 device, upgrade_us as f64 / 1000.0, revert_us as f64 / 1000.0,
+                 worst_us as f64 / 1000.0);
+    }
+
+    /// Check that upgrades to images the bootloader should not trust are rejected.
+    fn run_rejection_tests(&self, flashmap: &SimFlashMap, areadesc: &AreaDesc,
+                           slot0: &SlotInfo, slot1: &SlotInfo) -> bool {
         let mut failed = false;
 
         // Creates a badly signed image in slot1 to check that it is not
         // upgraded to
-        let mut bad_flash = flash.clone();
+        let mut bad_flash = flashmap.clone();
         let bad_slot1_image = Images {
-            slot0: &slot0,
-            slot1: &slot1,
-            primary: install_image(&mut bad_flash, slot0_base, 32784, false),
-            upgrade: install_image(&mut bad_flash, slot1_base, 41928, true),
+            slot0: slot0,
+            slot1: slot1,
+            primary: install_image(&mut bad_flash, slot0, 32784, self.make_tlv(), false),
+            upgrade: install_image(&mut bad_flash, slot1, 41928, self.make_tlv(), true),
         };
 
-        failed |= run_signfail_upgrade(&bad_flash, &areadesc, &bad_slot1_image);
+        failed |= run_signfail_upgrade(&bad_flash, areadesc, &bad_slot1_image);
 
-        let images = Images {
-            slot0: &slot0,
-            slot1: &slot1,
-            primary: install_image(&mut flash, slot0_base, 32784, false),
-            upgrade: install_image(&mut flash, slot1_base, 41928, false),
-        };
+        // A PSS signature must not be accepted just because the header claims PKCS#1 v1.5.
+        if cfg!(feature = "sig-rsa-pkcs1-15") {
+            let mut bad_flash = flashmap.clone();
+            let mut tlv = TlvGen::new_rsa_pkcs15_with_pss_sig();
+            if let Some(key) = self.keys.first() {
+                tlv.set_key(key.clone());
+            }
+            let bad_padding_image = Images {
+                slot0: slot0,
+                slot1: slot1,
+                primary: install_image(&mut bad_flash, slot0, 32784, self.make_tlv(), false),
+                upgrade: install_image(&mut bad_flash, slot1, 41928, tlv, false),
+            };
+
+            failed |= run_signfail_upgrade(&bad_flash, areadesc, &bad_padding_image);
+        }
 
-        failed |= run_norevert_newimage(&flash, &areadesc, &images);
+        // An image signed by a key that the bootloader doesn't know must not be upgraded to.
+        if let Some(key) = untrusted_key() {
+            let mut bad_flash = flashmap.clone();
+            let mut tlv = make_tlv();
+            tlv.set_key(key);
+            let untrusted_image = Images {
+                slot0: slot0,
+                slot1: slot1,
+                primary: install_image(&mut bad_flash, slot0, 32784, self.make_tlv(), false),
+                upgrade: install_image(&mut bad_flash, slot1, 41928, tlv, false),
+            };
+
+            failed |= run_signfail_upgrade(&bad_flash, areadesc, &untrusted_image);
+        }
 
-        mark_upgrade(&mut flash, &images.slot1);
+        if Caps::signed() {
+            failed |= self.run_key_id_tests(flashmap, areadesc, slot0, slot1);
+        }
 
-        // upgrades without fails, counts number of flash operations
-        let total_count = match run_basic_upgrade(&flash, &areadesc, &images) {
-            Ok(v)  => v,
-            Err(_) => {
-                self.failures += 1;
-                return;
-            },
-        };
+        failed |= self.run_image_validation_tests(flashmap, areadesc, slot0, slot1);
 
-        failed |= run_basic_revert(&flash, &areadesc, &images);
-        failed |= run_revert_with_fails(&flash, &areadesc, &images, total_count);
-        failed |= run_perm_with_fails(&flash, &areadesc, &images, total_count);
-        failed |= run_perm_with_random_fails(&flash, &areadesc, &images,
-                                             total_count, 5);
-        failed |= run_norevert(&flash, &areadesc, &images);
+        failed
+    }
 
-        //show_flash(&flash);
+    /// Check that the key_id in the header selects the key used to verify the image.  An image is
+    /// rejected if its key_id names a different trusted key, or one past the end of the table, and
+    /// an upgrade can move to an image signed by a different trusted key.
+    fn run_key_id_tests(&self, flashmap: &SimFlashMap, areadesc: &AreaDesc,
+                        slot0: &SlotInfo, slot1: &SlotInfo) -> bool {
+        let key_count = c::boot_key_count();
+        let mut failed = false;
 
-        if failed {
-            self.failures += 1;
-        } else {
-            self.passes += 1;
+        if self.keys.len() != key_count {
+            warn!("Have {} signing keys, but the bootloader trusts {}", self.keys.len(), key_count);
+        }
+
+        // Signed by the first key, but claiming to be signed by the second.
+        if key_count > 1 {
+            info!("Try upgrade with mismatched key_id");
+            let mut bad_flash = flashmap.clone();
+            let mut tlv = self.make_tlv();
+            tlv.set_key_id(1);
+            let images = Images {
+                slot0: slot0,
+                slot1: slot1,
+                primary: install_image(&mut bad_flash, slot0, 32784, self.make_tlv(), false),
+                upgrade: install_image(&mut bad_flash, slot1, 41928, tlv, false),
+            };
+
+            failed |= run_signfail_upgrade(&bad_flash, areadesc, &images);
+        }
+
+        // A key_id past the end of the key table.
+        if key_count < 256 {
+            info!("Try upgrade with out of range key_id {}", key_count);
+            let mut bad_flash = flashmap.clone();
+            let mut tlv = self.make_tlv();
+            tlv.set_key_id(key_count as u8);
+            let images = Images {
+                slot0: slot0,
+                slot1: slot1,
+                primary: install_image(&mut bad_flash, slot0, 32784, self.make_tlv(), false),
+                upgrade: install_image(&mut bad_flash, slot1, 41928, tlv, false),
+            };
+
+            failed |= run_signfail_upgrade(&bad_flash, areadesc, &images);
+        }
+
+        // Key rotation: the upgrade is signed by a different trusted key than the primary image.
+        if key_count > 1 && self.keys.len() > 1 {
+            info!("Try upgrade with rotated key");
+            let mut fl = flashmap.clone();
+            let images = Images {
+                slot0: slot0,
+                slot1: slot1,
+                primary: install_image(&mut fl, slot0, 32784, self.make_tlv_with_key(0),
+                                       false),
+                upgrade: install_image(&mut fl, slot1, 41928, self.make_tlv_with_key(1),
+                                       false),
+            };
+            mark_upgrade(&mut fl, &images.slot1);
+
+            if run_basic_upgrade(&fl, areadesc, &images).is_err() {
+                error!("Upgrade to image signed with rotated key failed");
+                failed = true;
+            }
+            failed |= run_basic_revert(&fl, areadesc, &images);
         }
+
+        failed
+    }
+
+    /// Check each of the fields that the bootloader validates, by installing an upgrade with just
+    /// that field broken, and making sure it is rejected.  Headers whose sizes put the TLV area in
+    /// the wrong place are rejected too.  Some fields have more than one valid value, and those
+    /// upgrades must still succeed.
+    fn run_image_validation_tests(&self, flashmap: &SimFlashMap, areadesc: &AreaDesc,
+                                  slot0: &SlotInfo, slot1: &SlotInfo) -> bool {
+        let mut failed = false;
+
+        let upgrade = || image_builder(slot1.base_off, 41928, self.make_tlv());
+        let flags = self.make_tlv().get_flags();
+
+        let mut bad = vec![
+            ("bad magic", upgrade().magic(0x12345678).build()),
+            ("no SHA256 flag", upgrade().flags(flags & !IMAGE_F_SHA256).build()),
+            ("non-bootable flag", upgrade().flags(flags | IMAGE_F_NON_BOOTABLE).build()),
+            ("empty TLV area", upgrade().tlv_size(0).build()),
+            ("short SHA256 TLV", upgrade().extra_tlv(IMAGE_TLV_SHA256, &[0; 16]).build()),
+            ("second SHA256 TLV", upgrade().extra_tlv(IMAGE_TLV_SHA256, &[0; 32]).build()),
+            ("corrupt body", upgrade().corrupt(IMAGE_HEADER_SIZE + 100).build()),
+        ];
+        if Caps::signed() {
+            bad.push(("no signature flag", upgrade().flags(IMAGE_F_SHA256).build()));
+
+            // Skip the hash TLV, and the signature's own TLV header.
+            let image = upgrade();
+            let sig_off = image.tlv_offset() + 4 + 32 + 4;
+            bad.push(("corrupt signature", image.corrupt(sig_off + 10).build()));
+        }
+
+        // The bootloader doesn't check hdr_size or img_size, but they place the TLV area, so
+        // getting them wrong leaves it looking for the hash among the wrong bytes.
+        let misplaced = vec![
+            ("TLV area moved back by a short header", upgrade().hdr_size(16)),
+            ("TLV area moved on by a long img_size", upgrade().img_size(41928 + 4)),
+        ];
+        for (name, image) in misplaced {
+            let tlv_off = image.tlv_offset();
+            let image = image.build();
+            if !tlv_misplaced(&image, tlv_off) {
+                error!("Image with {} has its hash TLV where the header says", name);
+                failed = true;
+            }
+            bad.push((name, image));
+        }
+
+        for (name, image) in bad {
+            info!("Try upgrade with {}", name);
+            let mut bad_flash = flashmap.clone();
+            let images = Images {
+                slot0: slot0,
+                slot1: slot1,
+                primary: install_image(&mut bad_flash, slot0, 32784, self.make_tlv(), false),
+                upgrade: write_image(&mut bad_flash, slot1, image),
+            };
+
+            if run_signfail_upgrade(&bad_flash, areadesc, &images) {
+                error!("Upgrade with {} was not rejected", name);
+                failed = true;
+            }
+        }
+
+        let good = vec![
+            ("long header", upgrade().hdr_size(64)),
+            ("unknown TLV", upgrade().extra_tlv(0x50, &[0x55; 12])),
+        ];
+
+        for (name, image) in good {
+            info!("Try upgrade with {}", name);
+            let mut fl = flashmap.clone();
+            let images = Images {
+                slot0: slot0,
+                slot1: slot1,
+                primary: install_image(&mut fl, slot0, 32784, self.make_tlv(), false),
+                upgrade: write_image(&mut fl, slot1, image.build()),
+            };
+            mark_upgrade(&mut fl, &images.slot1);
+
+            if run_basic_upgrade(&fl, areadesc, &images).is_err() {
+                error!("Upgrade with {} failed", name);
+                failed = true;
+            }
+        }
+
+        failed
+    }
+}
+
+// Flash timings for each device, roughly the typical figures from the datasheets.
+const STM32F4_TIMING: Timing = Timing {
+    erase_sector_us: 150_000,
+    erase_kb_us: 6_700,
+    program_word_us: 16,
+    word_size: 4,
+    read_kb_us: 10,
+};
+
+const K64F_TIMING: Timing = Timing {
+    erase_sector_us: 14_000,
+    erase_kb_us: 0,
+    program_word_us: 65,
+    word_size: 8,
+    read_kb_us: 10,
+};
+
+const NRF52840_TIMING: Timing = Timing {
+    erase_sector_us: 85_000,
+    erase_kb_us: 0,
+    program_word_us: 41,
+    word_size: 4,
+    read_kb_us: 16,
+};
+
+const SPI_NOR_TIMING: Timing = Timing {
+    erase_sector_us: 45_000,
+    erase_kb_us: 0,
+    program_word_us: 11,
+    word_size: 4,
+    read_kb_us: 250,
+};
+
+/// Build the simulated flash devices, and the partition layout on them, for the given device type.
+/// The device ids are 0 for the internal flash and 1 for an external flash part.
+fn make_device(device: DeviceName, align: u8) -> (SimFlashMap, AreaDesc) {
+    let mut flashmap = SimFlashMap::new();
+    match device {
+        DeviceName::Stm32f4 => {
+            // STM style flash.  Large sectors, with a large scratch area.
+            let flash = SimFlash::new(vec![16 * 1024, 16 * 1024, 16 * 1024, 16 * 1024,
+                                      64 * 1024,
+                                      128 * 1024, 128 * 1024, 128 * 1024],
+                                      align as usize)
+                .with_timing(STM32F4_TIMING);
+            flashmap.insert(0, flash);
+            let mut areadesc = AreaDesc::new(&flashmap);
+            areadesc.add_image(0x020000, 0x020000, FlashId::Image0, 0);
+            areadesc.add_image(0x040000, 0x020000, FlashId::Image1, 0);
+            areadesc.add_image(0x060000, 0x020000, FlashId::ImageScratch, 0);
+            (flashmap, areadesc)
+        }
+        DeviceName::K64f => {
+            // NXP style flash.  Small sectors, one small sector for scratch.
+            let flash = SimFlash::new(vec![4096; 128], align as usize).with_timing(K64F_TIMING);
+            flashmap.insert(0, flash);
+
+            let mut areadesc = AreaDesc::new(&flashmap);
+            areadesc.add_image(0x020000, 0x020000, FlashId::Image0, 0);
+            areadesc.add_image(0x040000, 0x020000, FlashId::Image1, 0);
+            areadesc.add_image(0x060000, 0x001000, FlashId::ImageScratch, 0);
+            (flashmap, areadesc)
+        }
+        DeviceName::K64fBig => {
+            // Simulating an STM style flash on top of an NXP style flash.  Underlying flash device
+            // uses small sectors, but we tell the bootloader they are large.
+            let flash = SimFlash::new(vec![4096; 128], align as usize).with_timing(K64F_TIMING);
+            flashmap.insert(0, flash);
+
+            let mut areadesc = AreaDesc::new(&flashmap);
+            areadesc.add_simple_image(0x020000, 0x020000, FlashId::Image0, 0);
+            areadesc.add_simple_image(0x040000, 0x020000, FlashId::Image1, 0);
+            areadesc.add_simple_image(0x060000, 0x020000, FlashId::ImageScratch, 0);
+            (flashmap, areadesc)
+        }
+        DeviceName::K64fReordered => {
+            // NXP style flash, with slot 1 first and slot 0 last, and a gap for a settings
+            // partition before the scratch area.  The scratch area is large enough for the trailer
+            // at any alignment.
+            let flash = SimFlash::new(vec![4096; 128], align as usize).with_timing(K64F_TIMING);
+            flashmap.insert(0, flash);
+
+            let mut areadesc = AreaDesc::new(&flashmap);
+            areadesc.add_image(0x050000, 0x020000, FlashId::Image0, 0);
+            areadesc.add_image(0x020000, 0x020000, FlashId::Image1, 0);
+            areadesc.add_image(0x044000, 0x004000, FlashId::ImageScratch, 0);
+            (flashmap, areadesc)
+        }
+        DeviceName::K64fNoScratch => {
+            // NXP style flash, with no scratch area, for overwrite only upgrades.
+            let flash = SimFlash::new(vec![4096; 128], align as usize).with_timing(K64F_TIMING);
+            flashmap.insert(0, flash);
+
+            let mut areadesc = AreaDesc::new(&flashmap);
+            areadesc.add_image(0x020000, 0x020000, FlashId::Image0, 0);
+            areadesc.add_image(0x040000, 0x020000, FlashId::Image1, 0);
+            (flashmap, areadesc)
+        }
+        DeviceName::K64fClearBits | DeviceName::K64fRewrite | DeviceName::K64fErasedZero => {
+            // The k64f layout, on flash that lets written locations be written again, either
+            // clearing more bits or with any value, or that erases to zero.
+            let flash = SimFlash::new(vec![4096; 128], align as usize).with_timing(K64F_TIMING);
+            let flash = match device {
+                DeviceName::K64fClearBits => flash.with_write_policy(WritePolicy::ClearBits),
+                DeviceName::K64fRewrite => flash.with_write_policy(WritePolicy::Rewrite),
+                _ => flash.with_erased_val(0),
+            };
+            flashmap.insert(0, flash);
+
+            let mut areadesc = AreaDesc::new(&flashmap);
+            areadesc.add_image(0x020000, 0x020000, FlashId::Image0, 0);
+            areadesc.add_image(0x040000, 0x020000, FlashId::Image1, 0);
+            areadesc.add_image(0x060000, 0x001000, FlashId::ImageScratch, 0);
+            (flashmap, areadesc)
+        }
+        DeviceName::Nrf52840 => {
+            // Simulating the flash on the nrf52840 with partitions set up so that the scratch size
+            // does not divide into the image size.
+            let flash = SimFlash::new(vec![4096; 128], align as usize)
+                .with_timing(NRF52840_TIMING);
+            flashmap.insert(0, flash);
+
+            let mut areadesc = AreaDesc::new(&flashmap);
+            areadesc.add_image(0x008000, 0x034000, FlashId::Image0, 0);
+            areadesc.add_image(0x03c000, 0x034000, FlashId::Image1, 0);
+            areadesc.add_image(0x070000, 0x00d000, FlashId::ImageScratch, 0);
+            (flashmap, areadesc)
+        }
+        DeviceName::Nrf52840Gaps => {
+            // The nrf52840 with the scratch area straight after the bootloader, and gaps for
+            // settings and NVS partitions between the scratch area and each slot.
+            let flash = SimFlash::new(vec![4096; 128], align as usize)
+                .with_timing(NRF52840_TIMING);
+            flashmap.insert(0, flash);
+
+            let mut areadesc = AreaDesc::new(&flashmap);
+            areadesc.add_image(0x018000, 0x030000, FlashId::Image0, 0);
+            areadesc.add_image(0x04c000, 0x030000, FlashId::Image1, 0);
+            areadesc.add_image(0x008000, 0x00d000, FlashId::ImageScratch, 0);
+            (flashmap, areadesc)
+        }
+        DeviceName::Nrf52840NoScratch => {
+            // The nrf52840 with no scratch area, for overwrite only upgrades, so the slots can
+            // take the rest of the flash.
+            let flash = SimFlash::new(vec![4096; 128], align as usize)
+                .with_timing(NRF52840_TIMING);
+            flashmap.insert(0, flash);
+
+            let mut areadesc = AreaDesc::new(&flashmap);
+            areadesc.add_image(0x008000, 0x03c000, FlashId::Image0, 0);
+            areadesc.add_image(0x044000, 0x03c000, FlashId::Image1, 0);
+            (flashmap, areadesc)
+        }
+        DeviceName::Nrf52840SpiFlash => {
+            // The nrf52840 running from its internal flash, with the upgrade on an external SPI
+            // NOR part.  The NOR part has 4K subsectors, so the slots still match, but can be
+            // written a byte at a time, whatever the alignment of the internal flash.  The
+            // bootloader writes the swap status with the alignment of slot 0, so the scratch area
+            // has to stay on the same device.
+            let flash0 = SimFlash::new(vec![4096; 128], align as usize)
+                .with_timing(NRF52840_TIMING);
+            let flash1 = SimFlash::new(vec![4096; 256], 1)
+                .with_timing(SPI_NOR_TIMING);
+            flashmap.insert(0, flash0);
+            flashmap.insert(1, flash1);
+
+            let mut areadesc = AreaDesc::new(&flashmap);
+            areadesc.add_image(0x008000, 0x068000, FlashId::Image0, 0);
+            areadesc.add_image(0x000000, 0x068000, FlashId::Image1, 1);
+            areadesc.add_image(0x070000, 0x00d000, FlashId::ImageScratch, 0);
+            (flashmap, areadesc)
+        }
+    }
+}
+
+/// The number of erases each sector of the simulated devices is rated for, typical of both
+/// internal flash and SPI NOR parts.
+const ENDURANCE: u32 = 10_000;
+
+/// Whether any sector of the flash devices has been erased more than `ENDURANCE` times.
+fn worn_out(flashmap: &SimFlashMap) -> bool {
+    flashmap.values().any(|flash| flash.erase_counts().iter().any(|&n| n >= ENDURANCE))
+}
+
+/// Whether a layout problem is one that some of the built in devices are expected to have: the
+/// trailer doesn't fit at the larger alignments, and the devices meant for overwrite only upgrades
+/// have no scratch area.  These devices are skipped, rather than failed.
+fn expected_layout_error(error: &LayoutError) -> bool {
+    match *error {
+        LayoutError::TrailerTooBig { .. } => true,
+        LayoutError::Missing(FlashId::ImageScratch) => !cfg!(feature = "overwrite-only"),
+        _ => false,
+    }
+}
+
+/// Whether the header of an image puts its TLV area somewhere other than where the TLV entries were
+/// written, at `tlv_off`, so that there is no hash TLV where the bootloader looks for one.
+fn tlv_misplaced(image: &[u8], tlv_off: usize) -> bool {
+    let header = match ImageHeader::parse(image) {
+        Ok(header) => header,
+        Err(_) => return false,
+    };
+    let claimed = header.hdr_size as usize + header.img_size as usize;
+    claimed != tlv_off &&
+        image.get(claimed..).map_or(true, |tlvs| !tlvs.starts_with(&[IMAGE_TLV_SHA256, 0, 32, 0]))
+}
+
+/// Whether the bootloader can run on these flash devices.  It assumes that erased flash reads as
+/// 0xff, so an unwritten trailer on a device that erases to anything else looks corrupt.
+fn bootloader_handles(flashmap: &SimFlashMap) -> bool {
+    flashmap.values().all(|flash| flash.erased_val() == c::boot_erased_val())
+}
+
+/// Locate the image slots and their trailers in a layout.  The trailer is at the end of each slot.
+fn make_slots(areadesc: &AreaDesc) -> (SlotInfo, SlotInfo) {
+    let (slot0_base, slot0_len, slot0_dev_id) = areadesc.find(FlashId::Image0);
+    let (slot1_base, slot1_len, slot1_dev_id) = areadesc.find(FlashId::Image1);
+
+    let offset_from_end = c::boot_magic_area_sz() + c::boot_max_align() * 2;
+
+    let slot0 = SlotInfo {
+        base_off: slot0_base as usize,
+        trailer_off: slot0_base + slot0_len - offset_from_end,
+        len: slot0_len,
+        dev_id: slot0_dev_id,
+    };
+
+    let slot1 = SlotInfo {
+        base_off: slot1_base as usize,
+        trailer_off: slot1_base + slot1_len - offset_from_end,
+        len: slot1_len,
+        dev_id: slot1_dev_id,
+    };
+
+    (slot0, slot1)
+}
+
+/// The simulated time spent in flash operations, over all of the devices.
+fn elapsed_us(flashmap: &SimFlashMap) -> u64 {
+    flashmap.values().map(|flash| flash.elapsed_us()).sum()
+}
+
+fn reset_clocks(flashmap: &SimFlashMap) {
+    for flash in flashmap.values() {
+        flash.reset_clock();
     }
 }
 
@@ -303,28 +1180,31 @@ impl RunStatus {
 ///
 /// Returns the number of flash operations which can later be used to
 /// inject failures at chosen steps.
-fn run_basic_upgrade(flash: &SimFlash, areadesc: &AreaDesc, images: &Images)
+fn run_basic_upgrade(flashmap: &SimFlashMap, areadesc: &AreaDesc, images: &Images)
                      -> Result<i32, ()> {
-    let (fl, total_count) = try_upgrade(&flash, &areadesc, &images, None);
+    let (fl, total_count) = try_upgrade(&flashmap, &areadesc, &images, None);
     info!("Total flash operation count={}", total_count);
 
-    if !verify_image(&fl, images.slot0.base_off, &images.upgrade) {
+    if !verify_image(&fl, images.slot0, &images.upgrade) {
         warn!("Image mismatch after first boot");
         Err(())
+    } else if !verify_slot1_done(&fl, images.slot1) {
+        warn!("Upgrade left in Slot 1 after first boot");
+        Err(())
     } else {
         Ok(total_count)
     }
 }
 
-fn run_basic_revert(flash: &SimFlash, areadesc: &AreaDesc, images: &Images) -> bool {
+fn run_basic_revert(flashmap: &SimFlashMap, areadesc: &AreaDesc, images: &Images) -> bool {
     let mut fails = 0;
 
     // FIXME: this test would also pass if no swap is ever performed???
     if Caps::SwapUpgrade.present() {
         for count in 2 .. 5 {
             info!("Try revert: {}", count);
-            let fl = try_revert(&flash, &areadesc, count);
-            if !verify_image(&fl, images.slot0.base_off, &images.primary) {
+            let fl = try_revert(&flashmap, &areadesc, count);
+            if !verify_image(&fl, images.slot0, &images.primary) {
                 error!("Revert failure on count {}", count);
                 fails += 1;
             }
@@ -334,34 +1214,33 @@ fn run_basic_revert(flash: &SimFlash, areadesc: &AreaDesc, images: &Images) -> b
     fails > 0
 }
 
-fn run_perm_with_fails(flash: &SimFlash, areadesc: &AreaDesc, images: &Images,
+fn run_perm_with_fails(flashmap: &SimFlashMap, areadesc: &AreaDesc, images: &Images,
                        total_flash_ops: i32) -> bool {
     let mut fails = 0;
 
     // Let's try an image halfway through.
     for i in 1 .. total_flash_ops {
         info!("Try interruption at {}", i);
-        let (fl, count) = try_upgrade(&flash, &areadesc, &images, Some(i));
+        let (fl, count) = try_upgrade(&flashmap, &areadesc, &images, Some(i));
         info!("Second boot, count={}", count);
-        if !verify_image(&fl, images.slot0.base_off, &images.upgrade) {
+        if !verify_image(&fl, images.slot0, &images.upgrade) {
             warn!("FAIL at step {} of {}", i, total_flash_ops);
             fails += 1;
         }
 
-        if !verify_trailer(&fl, images.slot0.trailer_off, MAGIC_VALID, IMAGE_OK,
+        if !verify_trailer(&fl, images.slot0, MAGIC_VALID, IMAGE_OK,
                            COPY_DONE) {
             warn!("Mismatched trailer for Slot 0");
             fails += 1;
         }
 
-        if !verify_trailer(&fl, images.slot1.trailer_off, MAGIC_UNSET, UNSET,
-                           UNSET) {
-            warn!("Mismatched trailer for Slot 1");
+        if !verify_slot1_done(&fl, images.slot1) {
+            warn!("Upgrade left in Slot 1");
             fails += 1;
         }
 
         if Caps::SwapUpgrade.present() {
-            if !verify_image(&fl, images.slot1.base_off, &images.primary) {
+            if !verify_image(&fl, images.slot1, &images.primary) {
                 warn!("Slot 1 FAIL at step {} of {}", i, total_flash_ops);
                 fails += 1;
             }
@@ -376,17 +1255,17 @@ fn run_perm_with_fails(flash: &SimFlash, areadesc: &AreaDesc, images: &Images,
     fails > 0
 }
 
-fn run_perm_with_random_fails(flash: &SimFlash, areadesc: &AreaDesc,
+fn run_perm_with_random_fails(flashmap: &SimFlashMap, areadesc: &AreaDesc,
                               images: &Images, total_flash_ops: i32,
                               total_fails: usize) -> bool {
     let mut fails = 0;
-    let (fl, total_counts) = try_random_fails(&flash, &areadesc, &images,
+    let (fl, total_counts) = try_random_fails(&flashmap, &areadesc, &images,
                                               total_flash_ops, total_fails);
     info!("Random interruptions at reset points={:?}", total_counts);
 
-    let slot0_ok = verify_image(&fl, images.slot0.base_off, &images.upgrade);
+    let slot0_ok = verify_image(&fl, images.slot0, &images.upgrade);
     let slot1_ok = if Caps::SwapUpgrade.present() {
-        verify_image(&fl, images.slot1.base_off, &images.primary)
+        verify_image(&fl, images.slot1, &images.primary)
     } else {
         true
     };
@@ -396,14 +1275,13 @@ fn run_perm_with_random_fails(flash: &SimFlash, areadesc: &AreaDesc,
                if slot1_ok { "ok" } else { "fail" });
         fails += 1;
     }
-    if !verify_trailer(&fl, images.slot0.trailer_off, MAGIC_VALID, IMAGE_OK,
+    if !verify_trailer(&fl, images.slot0, MAGIC_VALID, IMAGE_OK,
                        COPY_DONE) {
         error!("Mismatched trailer for Slot 0");
         fails += 1;
     }
-    if !verify_trailer(&fl, images.slot1.trailer_off, MAGIC_UNSET, UNSET,
-                       UNSET) {
-        error!("Mismatched trailer for Slot 1");
+    if !verify_slot1_done(&fl, images.slot1) {
+        error!("Upgrade left in Slot 1");
         fails += 1;
     }
 
@@ -414,14 +1292,184 @@ fn run_perm_with_random_fails(flash: &SimFlash, areadesc: &AreaDesc,
     fails > 0
 }
 
-fn run_revert_with_fails(flash: &SimFlash, areadesc: &AreaDesc, images: &Images,
+/// Run a permanent upgrade, and save the flash operations it makes to a file.  When there is more
+/// than one flash device, each device's operations go to a separate file, named by appending the
+/// device id to the path.
+fn save_upgrade_trace(flashmap: &SimFlashMap, areadesc: &AreaDesc, images: &Images,
+                      path: &str) -> bool {
+    let mut fl = flashmap.clone();
+    mark_permanent_upgrade(&mut fl, &images.slot1);
+
+    let mut tfl: HashMap<u8, TraceFlash<SimFlash>> = fl.into_iter()
+        .map(|(id, flash)| (id, TraceFlash::new(flash)))
+        .collect();
+    c::set_flash_counter(0);
+    if c::boot_go(&mut tfl, &areadesc) != 0 {
+        warn!("Failed to upgrade while tracing");
+        return true;
+    }
+
+    let single = tfl.len() == 1;
+    for (dev_id, flash) in tfl {
+        let (_, mut trace) = flash.into_parts();
+        let path = if single { path.to_string() } else { format!("{}.{}", path, dev_id) };
+        trace.name_areas(&|off| areadesc.area_at(dev_id, off).map(|id| format!("{:?}", id)));
+        if let Err(e) = trace.save(&path) {
+            error!("Unable to save trace to {}: {}", path, e);
+            return true;
+        }
+        info!("Saved {} flash operations to {}", trace.ops.len(), path);
+    }
+
+    false
+}
+
+/// Wrap each flash device in a simulation, so that faults can be injected into it.
+fn fault_map(flashmap: &SimFlashMap) -> HashMap<u8, FaultFlash<SimFlash>> {
+    flashmap.iter().map(|(&id, flash)| (id, FaultFlash::new(flash.clone()))).collect()
+}
+
+/// Fail randomly chosen writes and erases on each device during a permanent upgrade, some of the
+/// writes after only part of their data has been written.  The bootloader may give up on the boot
+/// where the fault happens, even with an assertion, but once the flash is working again, the
+/// upgrade must complete as if the failed operation had been interrupted by a reset.
+fn run_with_flash_faults(flashmap: &SimFlashMap, areadesc: &AreaDesc, images: &Images,
+                         count: usize) -> bool {
+    let mut fl = flashmap.clone();
+    mark_permanent_upgrade(&mut fl, &images.slot1);
+
+    // Count the operations in an upgrade without faults.
+    let mut counter = fault_map(&fl);
+    c::set_flash_counter(0);
+    if c::boot_go(&mut counter, &areadesc) != 0 {
+        warn!("Failed to upgrade without faults");
+        return true;
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut faults = vec![];
+    for (&dev_id, flash) in &counter {
+        let writes = flash.write_count();
+        let erases = flash.erase_count();
+        for _ in 0 .. count {
+            if writes > 0 {
+                let n = Range::new(1, writes + 1).ind_sample(&mut rng);
+                faults.push((dev_id, Fault::FailWrite(n)));
+
+                // Stop on a write boundary, so that the part before the failure is written.
+                let n = Range::new(1, writes + 1).ind_sample(&mut rng);
+                let len = flash.align() * Range::new(0, 4).ind_sample(&mut rng);
+                faults.push((dev_id, Fault::PartialWrite(n, len)));
+            }
+            if erases > 0 {
+                let n = Range::new(1, erases + 1).ind_sample(&mut rng);
+                faults.push((dev_id, Fault::FailErase(n)));
+            }
+        }
+    }
+
+    let mut fails = 0;
+    for (dev_id, fault) in faults {
+        info!("Try upgrade with {:?} on device {}", fault, dev_id);
+        let mut ffl = fault_map(&fl);
+        ffl.get_mut(&dev_id).unwrap().add_fault(fault.clone());
+
+        // Whatever the bootloader does about the failure, it mustn't leave the flash in a state it
+        // can't recover from.  If the fault never happened, it has no excuse to fail at all.
+        c::set_flash_counter(0);
+        match c::boot_go(&mut ffl, &areadesc) {
+            0 => (),
+            x if ffl[&dev_id].injected() > 0 => info!("Boot with {:?} returned {}", fault, x),
+            x => {
+                warn!("Boot returned {} without {:?} happening", x, fault);
+                fails += 1;
+                continue;
+            }
+        }
+
+        let mut ffl: SimFlashMap = ffl.into_iter().map(|(id, flash)| (id, flash.into_inner()))
+            .collect();
+        c::set_flash_counter(0);
+        if c::boot_go(&mut ffl, &areadesc) != 0 {
+            warn!("Failed boot after {:?}", fault);
+            fails += 1;
+            continue;
+        }
+
+        if !verify_image(&ffl, images.slot0, &images.upgrade) {
+            warn!("Image mismatch after {:?}", fault);
+            fails += 1;
+        }
+
+        if !verify_trailer(&ffl, images.slot0, MAGIC_VALID, IMAGE_OK,
+                           COPY_DONE) {
+            warn!("Mismatched trailer for Slot 0 after {:?}", fault);
+            fails += 1;
+        }
+    }
+
+    if fails > 0 {
+        error!("{} failures with injected flash faults", fails);
+    }
+
+    fails > 0
+}
+
+/// Flip a bit in a randomly chosen byte of the upgrade's header or body, as the bootloader reads it.
+/// The upgrade must be rejected, and the primary image booted, without an assertion.
+fn run_with_bit_flips(flashmap: &SimFlashMap, areadesc: &AreaDesc, images: &Images,
+                      count: usize) -> bool {
+    let mut fl = flashmap.clone();
+    mark_permanent_upgrade(&mut fl, &images.slot1);
+
+    // The hash covers the header and body, so a flip anywhere in them makes the image invalid.
+    let payload_len = match Image::parse(&images.upgrade) {
+        Ok(image) => image.payload().len(),
+        Err(e) => {
+            warn!("Unable to parse the upgrade: {}", e);
+            return true;
+        }
+    };
+
+    let mut rng = rand::thread_rng();
+    let mut fails = 0;
+    for _ in 0 .. count {
+        let pos = images.slot1.base_off + Range::new(0, payload_len).ind_sample(&mut rng);
+        let fault = Fault::BitFlip(pos, 1 << Range::new(0, 8).ind_sample(&mut rng));
+        info!("Try upgrade with {:?} on device {}", fault, images.slot1.dev_id);
+        let mut ffl = fault_map(&fl);
+        ffl.get_mut(&images.slot1.dev_id).unwrap().add_fault(fault.clone());
+
+        c::set_flash_counter(0);
+        if c::boot_go(&mut ffl, &areadesc) != 0 {
+            warn!("Failed boot with {:?}", fault);
+            fails += 1;
+            continue;
+        }
+
+        let ffl: SimFlashMap = ffl.into_iter().map(|(id, flash)| (id, flash.into_inner()))
+            .collect();
+        if !verify_image(&ffl, images.slot0, &images.primary) {
+            warn!("Upgraded despite {:?}", fault);
+            fails += 1;
+        }
+    }
+
+    if fails > 0 {
+        error!("{} failures with flipped bits in the upgrade", fails);
+    }
+
+    fails > 0
+}
+
+fn run_revert_with_fails(flashmap: &SimFlashMap, areadesc: &AreaDesc, images: &Images,
                          total_count: i32) -> bool {
     let mut fails = 0;
 
     if Caps::SwapUpgrade.present() {
         for i in 1 .. (total_count - 1) {
             info!("Try interruption at {}", i);
-            if try_revert_with_fail_at(&flash, &areadesc, &images, i) {
+            if try_revert_with_fail_at(&flashmap, &areadesc, &images, i) {
                 error!("Revert failed at interruption {}", i);
                 fails += 1;
             }
@@ -431,8 +1479,8 @@ fn run_revert_with_fails(flash: &SimFlash, areadesc: &AreaDesc, images: &Images,
     fails > 0
 }
 
-fn run_norevert(flash: &SimFlash, areadesc: &AreaDesc, images: &Images) -> bool {
-    let mut fl = flash.clone();
+fn run_norevert(flashmap: &SimFlashMap, areadesc: &AreaDesc, images: &Images) -> bool {
+    let mut fl = flashmap.clone();
     let mut fails = 0;
 
     info!("Try norevert");
@@ -447,25 +1495,24 @@ fn run_norevert(flash: &SimFlash, areadesc: &AreaDesc, images: &Images) -> bool
     //FIXME: copy_done is written by boot_go, is it ok if no copy
     //       was ever done?
 
-    if !verify_image(&fl, images.slot0.base_off, &images.upgrade) {
+    if !verify_image(&fl, images.slot0, &images.upgrade) {
         warn!("Slot 0 image verification FAIL");
         fails += 1;
     }
-    if !verify_trailer(&fl, images.slot0.trailer_off, MAGIC_VALID, UNSET,
+    if !verify_trailer(&fl, images.slot0, MAGIC_VALID, UNSET,
                        COPY_DONE) {
         warn!("Mismatched trailer for Slot 0");
         fails += 1;
     }
-    if !verify_trailer(&fl, images.slot1.trailer_off, MAGIC_UNSET, UNSET,
-                       UNSET) {
-        warn!("Mismatched trailer for Slot 1");
+    if !verify_slot1_done(&fl, images.slot1) {
+        warn!("Upgrade left in Slot 1");
         fails += 1;
     }
 
     // Marks image in slot0 as permanent, no revert should happen...
     mark_permanent_upgrade(&mut fl, &images.slot0);
 
-    if !verify_trailer(&fl, images.slot0.trailer_off, MAGIC_VALID, IMAGE_OK,
+    if !verify_trailer(&fl, images.slot0, MAGIC_VALID, IMAGE_OK,
                        COPY_DONE) {
         warn!("Mismatched trailer for Slot 0");
         fails += 1;
@@ -476,12 +1523,12 @@ fn run_norevert(flash: &SimFlash, areadesc: &AreaDesc, images: &Images) -> bool
         fails += 1;
     }
 
-    if !verify_trailer(&fl, images.slot0.trailer_off, MAGIC_VALID, IMAGE_OK,
+    if !verify_trailer(&fl, images.slot0, MAGIC_VALID, IMAGE_OK,
                        COPY_DONE) {
         warn!("Mismatched trailer for Slot 0");
         fails += 1;
     }
-    if !verify_image(&fl, images.slot0.base_off, &images.upgrade) {
+    if !verify_image(&fl, images.slot0, &images.upgrade) {
         warn!("Failed image verification");
         fails += 1;
     }
@@ -495,9 +1542,9 @@ fn run_norevert(flash: &SimFlash, areadesc: &AreaDesc, images: &Images) -> bool
 
 // Tests a new image written to slot0 that already has magic and image_ok set
 // while there is no image on slot1, so no revert should ever happen...
-fn run_norevert_newimage(flash: &SimFlash, areadesc: &AreaDesc,
+fn run_norevert_newimage(flashmap: &SimFlashMap, areadesc: &AreaDesc,
                          images: &Images) -> bool {
-    let mut fl = flash.clone();
+    let mut fl = flashmap.clone();
     let mut fails = 0;
 
     info!("Try non-revert on imgtool generated image");
@@ -506,7 +1553,7 @@ fn run_norevert_newimage(flash: &SimFlash, areadesc: &AreaDesc,
     mark_upgrade(&mut fl, &images.slot0);
 
     // This simulates writing an image created by imgtool to Slot 0
-    if !verify_trailer(&fl, images.slot0.trailer_off, MAGIC_VALID, UNSET, UNSET) {
+    if !verify_trailer(&fl, images.slot0, MAGIC_VALID, UNSET, UNSET) {
         warn!("Mismatched trailer for Slot 0");
         fails += 1;
     }
@@ -518,16 +1565,16 @@ fn run_norevert_newimage(flash: &SimFlash, areadesc: &AreaDesc,
     }
 
     // State should not have changed
-    if !verify_image(&fl, images.slot0.base_off, &images.primary) {
+    if !verify_image(&fl, images.slot0, &images.primary) {
         warn!("Failed image verification");
         fails += 1;
     }
-    if !verify_trailer(&fl, images.slot0.trailer_off, MAGIC_VALID, UNSET,
+    if !verify_trailer(&fl, images.slot0, MAGIC_VALID, UNSET,
                        UNSET) {
         warn!("Mismatched trailer for Slot 0");
         fails += 1;
     }
-    if !verify_trailer(&fl, images.slot1.trailer_off, MAGIC_UNSET, UNSET,
+    if !verify_trailer(&fl, images.slot1, MAGIC_UNSET, UNSET,
                        UNSET) {
         warn!("Mismatched trailer for Slot 1");
         fails += 1;
@@ -542,9 +1589,9 @@ fn run_norevert_newimage(flash: &SimFlash, areadesc: &AreaDesc,
 
 // Tests a new image written to slot0 that already has magic and image_ok set
 // while there is no image on slot1, so no revert should ever happen...
-fn run_signfail_upgrade(flash: &SimFlash, areadesc: &AreaDesc,
+fn run_signfail_upgrade(flashmap: &SimFlashMap, areadesc: &AreaDesc,
                         images: &Images) -> bool {
-    let mut fl = flash.clone();
+    let mut fl = flashmap.clone();
     let mut fails = 0;
 
     info!("Try upgrade image with bad signature");
@@ -554,7 +1601,7 @@ fn run_signfail_upgrade(flash: &SimFlash, areadesc: &AreaDesc,
     mark_permanent_upgrade(&mut fl, &images.slot0);
     mark_upgrade(&mut fl, &images.slot1);
 
-    if !verify_trailer(&fl, images.slot0.trailer_off, MAGIC_VALID, IMAGE_OK,
+    if !verify_trailer(&fl, images.slot0, MAGIC_VALID, IMAGE_OK,
                        UNSET) {
         warn!("Mismatched trailer for Slot 0");
         fails += 1;
@@ -567,11 +1614,11 @@ fn run_signfail_upgrade(flash: &SimFlash, areadesc: &AreaDesc,
     }
 
     // State should not have changed
-    if !verify_image(&fl, images.slot0.base_off, &images.primary) {
+    if !verify_image(&fl, images.slot0, &images.primary) {
         warn!("Failed image verification");
         fails += 1;
     }
-    if !verify_trailer(&fl, images.slot0.trailer_off, MAGIC_VALID, IMAGE_OK,
+    if !verify_trailer(&fl, images.slot0, MAGIC_VALID, IMAGE_OK,
                        UNSET) {
         warn!("Mismatched trailer for Slot 0");
         fails += 1;
@@ -586,10 +1633,10 @@ fn run_signfail_upgrade(flash: &SimFlash, areadesc: &AreaDesc,
 
 /// Test a boot, optionally stopping after 'n' flash options.  Returns a count
 /// of the number of flash operations done total.
-fn try_upgrade(flash: &SimFlash, areadesc: &AreaDesc, images: &Images,
-               stop: Option<i32>) -> (SimFlash, i32) {
+fn try_upgrade(flashmap: &SimFlashMap, areadesc: &AreaDesc, images: &Images,
+               stop: Option<i32>) -> (SimFlashMap, i32) {
     // Clone the flash to have a new copy.
-    let mut fl = flash.clone();
+    let mut fl = flashmap.clone();
 
     mark_permanent_upgrade(&mut fl, &images.slot1);
 
@@ -613,8 +1660,8 @@ fn try_upgrade(flash: &SimFlash, areadesc: &AreaDesc, images: &Images,
     (fl, count - c::get_flash_counter())
 }
 
-fn try_revert(flash: &SimFlash, areadesc: &AreaDesc, count: usize) -> SimFlash {
-    let mut fl = flash.clone();
+fn try_revert(flashmap: &SimFlashMap, areadesc: &AreaDesc, count: usize) -> SimFlashMap {
+    let mut fl = flashmap.clone();
     c::set_flash_counter(0);
 
     // fl.write_file("image0.bin").unwrap();
@@ -625,9 +1672,9 @@ fn try_revert(flash: &SimFlash, areadesc: &AreaDesc, count: usize) -> SimFlash {
     fl
 }
 
-fn try_revert_with_fail_at(flash: &SimFlash, areadesc: &AreaDesc, images: &Images,
+fn try_revert_with_fail_at(flashmap: &SimFlashMap, areadesc: &AreaDesc, images: &Images,
                            stop: i32) -> bool {
-    let mut fl = flash.clone();
+    let mut fl = flashmap.clone();
     let mut x: i32;
     let mut fails = 0;
 
@@ -638,7 +1685,7 @@ fn try_revert_with_fail_at(flash: &SimFlash, areadesc: &AreaDesc, images: &Image
         fails += 1;
     }
 
-    if !verify_trailer(&fl, images.slot0.trailer_off, None, None, UNSET) {
+    if !verify_trailer(&fl, images.slot0, ANY_MAGIC, ANY, UNSET) {
         warn!("copy_done should be unset");
         fails += 1;
     }
@@ -650,20 +1697,20 @@ fn try_revert_with_fail_at(flash: &SimFlash, areadesc: &AreaDesc, images: &Image
         fails += 1;
     }
 
-    if !verify_image(&fl, images.slot0.base_off, &images.upgrade) {
+    if !verify_image(&fl, images.slot0, &images.upgrade) {
         warn!("Image in slot 0 before revert is invalid at stop={}", stop);
         fails += 1;
     }
-    if !verify_image(&fl, images.slot1.base_off, &images.primary) {
+    if !verify_image(&fl, images.slot1, &images.primary) {
         warn!("Image in slot 1 before revert is invalid at stop={}", stop);
         fails += 1;
     }
-    if !verify_trailer(&fl, images.slot0.trailer_off, MAGIC_VALID, UNSET,
+    if !verify_trailer(&fl, images.slot0, MAGIC_VALID, UNSET,
                        COPY_DONE) {
         warn!("Mismatched trailer for Slot 0 before revert");
         fails += 1;
     }
-    if !verify_trailer(&fl, images.slot1.trailer_off, MAGIC_UNSET, UNSET,
+    if !verify_trailer(&fl, images.slot1, MAGIC_UNSET, UNSET,
                        UNSET) {
         warn!("Mismatched trailer for Slot 1 before revert");
         fails += 1;
@@ -677,20 +1724,20 @@ fn try_revert_with_fail_at(flash: &SimFlash, areadesc: &AreaDesc, images: &Image
         fails += 1;
     }
 
-    if !verify_image(&fl, images.slot0.base_off, &images.primary) {
+    if !verify_image(&fl, images.slot0, &images.primary) {
         warn!("Image in slot 0 after revert is invalid at stop={}", stop);
         fails += 1;
     }
-    if !verify_image(&fl, images.slot1.base_off, &images.upgrade) {
+    if !verify_image(&fl, images.slot1, &images.upgrade) {
         warn!("Image in slot 1 after revert is invalid at stop={}", stop);
         fails += 1;
     }
-    if !verify_trailer(&fl, images.slot0.trailer_off, MAGIC_VALID, IMAGE_OK,
+    if !verify_trailer(&fl, images.slot0, MAGIC_VALID, IMAGE_OK,
                        COPY_DONE) {
         warn!("Mismatched trailer for Slot 1 after revert");
         fails += 1;
     }
-    if !verify_trailer(&fl, images.slot1.trailer_off, MAGIC_UNSET, UNSET,
+    if !verify_trailer(&fl, images.slot1, MAGIC_UNSET, UNSET,
                        UNSET) {
         warn!("Mismatched trailer for Slot 1 after revert");
         fails += 1;
@@ -699,9 +1746,9 @@ fn try_revert_with_fail_at(flash: &SimFlash, areadesc: &AreaDesc, images: &Image
     fails > 0
 }
 
-fn try_random_fails(flash: &SimFlash, areadesc: &AreaDesc, images: &Images,
-                    total_ops: i32,  count: usize) -> (SimFlash, Vec<i32>) {
-    let mut fl = flash.clone();
+fn try_random_fails(flashmap: &SimFlashMap, areadesc: &AreaDesc, images: &Images,
+                    total_ops: i32,  count: usize) -> (SimFlashMap, Vec<i32>) {
+    let mut fl = flashmap.clone();
 
     mark_permanent_upgrade(&mut fl, &images.slot1);
 
@@ -742,85 +1789,148 @@ fn show_flash(flash: &Flash) {
 }
 
 /// Install a "program" into the given image.  This fakes the image header, or at least all of the
-/// fields used by the given code.  Returns a copy of the image that was written.
-fn install_image(flash: &mut Flash, offset: usize, len: usize,
-                 bad_sig: bool) -> Vec<u8> {
-    let offset0 = offset;
-
-    let mut tlv = make_tlv();
-
-    // Generate a boot header.  Note that the size doesn't include the header.
-    let header = ImageHeader {
-        magic: 0x96f3b83c,
-        tlv_size: tlv.get_size(),
-        _pad1: 0,
-        hdr_size: 32,
-        key_id: 0,
-        _pad2: 0,
-        img_size: len as u32,
-        flags: tlv.get_flags(),
-        ver: ImageVersion {
+/// fields used by the given code.  The TLV generator determines how the image is signed.  Returns
+/// a copy of the image that was written.
+fn install_image(flashmap: &mut SimFlashMap, slot: &SlotInfo, len: usize,
+                 tlv: TlvGen, bad_sig: bool) -> Vec<u8> {
+    let image = image_builder(slot.base_off, len, tlv).zero_tlv(bad_sig).build();
+    let copy = write_image(flashmap, slot, image);
+
+    // The Rust parser should agree that a good image is intact.
+    if !bad_sig {
+        if let Err(e) = Image::parse_strict(&copy).and_then(|img| img.verify_hash()) {
+            panic!("Installed image does not parse: {}", e);
+        }
+    }
+
+    copy
+}
+
+/// Read a signed image from a file, exiting if it can't be read or isn't an mcuboot image.
+fn load_user_image(path: &str) -> Vec<u8> {
+    let mut data = vec![];
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut data)) {
+        error!("Unable to read image {}: {}", path, e);
+        process::exit(1);
+    }
+
+    if let Err(e) = Image::parse(&data).and_then(|img| img.verify_hash()) {
+        error!("{} is not a valid image: {}", path, e);
+        process::exit(1);
+    }
+
+    data
+}
+
+/// Install an image read from a file into the given slot.  Returns a copy of what was written, or
+/// None if the image doesn't fit in this device's layout.
+fn install_user_image(flashmap: &mut SimFlashMap, areadesc: &AreaDesc, id: FlashId,
+                      image: &[u8]) -> Option<Vec<u8>> {
+    if let Err(e) = areadesc.install_image(flashmap, id, image) {
+        error!("Unable to install image: {}", e);
+        return None;
+    }
+
+    let (base, _, dev_id) = areadesc.find(id);
+    let align = c::boot_max_align();
+    let mut copy = vec![0u8; (image.len() + align - 1) & !(align - 1)];
+    flashmap[&dev_id].read(base, &mut copy).unwrap();
+    Some(copy)
+}
+
+/// Start building the "program" that would be installed at the given offset.  The version is
+/// derived from the offset, and the body is pseudorandom data.
+fn image_builder(offset: usize, len: usize, tlv: TlvGen) -> ImageBuilder {
+    let mut buf = vec![0; len];
+    splat(&mut buf, offset + IMAGE_HEADER_SIZE);
+
+    ImageBuilder::new(tlv, buf)
+        .version(ImageVersion {
             major: (offset / (128 * 1024)) as u8,
             minor: 0,
             revision: 1,
             build_num: offset as u32,
-        },
-        _pad3: 0,
-    };
-
-    let b_header = header.as_raw();
-    tlv.add_bytes(&b_header);
-    /*
-    let b_header = unsafe { slice::from_raw_parts(&header as *const _ as *const u8,
-                                                  mem::size_of::<ImageHeader>()) };
-                                                  */
-    assert_eq!(b_header.len(), 32);
-    flash.write(offset, &b_header).unwrap();
-    let offset = offset + b_header.len();
-
-    // The core of the image itself is just pseudorandom data.
-    let mut buf = vec![0; len];
-    splat(&mut buf, offset);
-    tlv.add_bytes(&buf);
+        })
+}
 
-    // Get and append the TLV itself.
-    if bad_sig {
-        let good_sig = &mut tlv.make_tlv();
-        buf.append(&mut vec![0; good_sig.len()]);
-    } else {
-        buf.append(&mut tlv.make_tlv());
+/// Write a built image to the start of a slot, padded with the erased value to the largest flash
+/// alignment.  Returns a
+/// copy of what was written, to verify the image was installed correctly later.
+fn write_image(flashmap: &mut SimFlashMap, slot: &SlotInfo, mut image: Vec<u8>) -> Vec<u8> {
+    let flash = flashmap.get_mut(&slot.dev_id).unwrap();
+    while image.len() % c::boot_max_align() != 0 {
+        image.push(flash.erased_val());
     }
 
-    // Pad the block to a flash alignment (8 bytes).
-    while buf.len() % 8 != 0 {
-        buf.push(0xFF);
+    // The bootloader's idea of the trailer has to leave room for the image.
+    let trailer_sz = c::boot_trailer_sz(flash.align() as u8) as usize;
+    if image.len() + trailer_sz > slot.len {
+        panic!("Image of {} bytes and trailer of {} bytes don't fit in a {} byte slot",
+               image.len(), trailer_sz, slot.len);
     }
+    flash.write(slot.base_off, &image).unwrap();
 
-    flash.write(offset, &buf).unwrap();
-    let offset = offset + buf.len();
-
-    // Copy out the image so that we can verify that the image was installed correctly later.
-    let mut copy = vec![0u8; offset - offset0];
-    flash.read(offset0, &mut copy).unwrap();
-
+    let mut copy = vec![0u8; image.len()];
+    flash.read(slot.base_off, &mut copy).unwrap();
     copy
 }
 
 // The TLV in use depends on what kind of signature we are verifying.
-#[cfg(feature = "sig-rsa")]
+#[cfg(all(feature = "sig-rsa", not(feature = "sig-rsa-pkcs1-15")))]
 fn make_tlv() -> TlvGen {
     TlvGen::new_rsa_pss()
 }
 
-#[cfg(not(feature = "sig-rsa"))]
+#[cfg(feature = "sig-rsa-pkcs1-15")]
+fn make_tlv() -> TlvGen {
+    TlvGen::new_rsa_pkcs15()
+}
+
+#[cfg(feature = "sig-ecdsa")]
+fn make_tlv() -> TlvGen {
+    TlvGen::new_ecdsa_p256()
+}
+
+#[cfg(feature = "sig-ecdsa-p224")]
+fn make_tlv() -> TlvGen {
+    TlvGen::new_ecdsa_p224()
+}
+
+#[cfg(not(any(feature = "sig-rsa", feature = "sig-ecdsa", feature = "sig-ecdsa-p224")))]
 fn make_tlv() -> TlvGen {
     TlvGen::new_hash_only()
 }
 
-/// Verify that given image is present in the flash at the given offset.
-fn verify_image(flash: &Flash, offset: usize, buf: &[u8]) -> bool {
+// A key that the bootloader does not trust, matching the configured signature type.
+#[cfg(feature = "sig-rsa")]
+fn untrusted_key() -> Option<Arc<SigningKey>> {
+    let key = SigningKey::from_pem(include_bytes!("../keys/untrusted-rsa-2048.pem"));
+    Some(Arc::new(key.unwrap()))
+}
+
+#[cfg(feature = "sig-ecdsa")]
+fn untrusted_key() -> Option<Arc<SigningKey>> {
+    let key = SigningKey::from_pem(include_bytes!("../keys/untrusted-ec-p256-pkcs8.pem"));
+    Some(Arc::new(key.unwrap()))
+}
+
+#[cfg(feature = "sig-ecdsa-p224")]
+fn untrusted_key() -> Option<Arc<SigningKey>> {
+    let key = SigningKey::from_pem(include_bytes!("../keys/untrusted-ec-p224.pem"));
+    Some(Arc::new(key.unwrap()))
+}
+
+// Without signatures, any key is as good as another.
+#[cfg(not(any(feature = "sig-rsa", feature = "sig-ecdsa", feature = "sig-ecdsa-p224")))]
+fn untrusted_key() -> Option<Arc<SigningKey>> {
+    None
+}
+
+/// Verify that given image is present in the flash at the start of the given slot.
+fn verify_image(flashmap: &SimFlashMap, slot: &SlotInfo, buf: &[u8]) -> bool {
+    let offset = slot.base_off;
     let mut copy = vec![0u8; buf.len()];
-    flash.read(offset, &mut copy).unwrap();
+    flashmap[&slot.dev_id].read(offset, &mut copy).unwrap();
 
     if buf != &copy[..] {
         for i in 0 .. buf.len() {
@@ -835,17 +1945,65 @@ fn verify_image(flash: &Flash, offset: usize, buf: &[u8]) -> bool {
     }
 }
 
-fn verify_trailer(flash: &Flash, offset: usize,
-                  magic: Option<&[u8]>, image_ok: Option<u8>,
-                  copy_done: Option<u8>) -> bool {
-    let mut copy = vec![0u8; c::boot_magic_sz() + c::boot_max_align() * 2];
+/// Verify that slot 1 no longer holds an upgrade, once it has been done.  A swap leaves the old
+/// image there, with the trailer unset.  An overwrite only erases the first sector of the slot,
+/// with the image header, so the trailer that asked for the upgrade is left as it was.
+///
+/// This is all that is checked after an overwrite, rather than the whole slot being erased.  The
+/// bootloader on devices has always erased just the header, which is enough to stop the image
+/// being copied again, and the simulator tests that bootloader rather than a different one.
+fn verify_slot1_done(flashmap: &SimFlashMap, slot: &SlotInfo) -> bool {
+    if Caps::SwapUpgrade.present() {
+        verify_trailer(flashmap, slot, MAGIC_UNSET, UNSET, UNSET)
+    } else {
+        verify_header_erased(flashmap, slot)
+    }
+}
+
+/// Verify that the first sector of the given slot, which holds the image header, is erased.
+fn verify_header_erased(flashmap: &SimFlashMap, slot: &SlotInfo) -> bool {
+    let flash = &flashmap[&slot.dev_id];
+    let size = flash.sector_iter()
+        .find(|s| s.base == slot.base_off)
+        .expect("slot does not start on a sector")
+        .size;
+    let mut copy = vec![0u8; size];
+    flash.read(slot.base_off, &mut copy).unwrap();
+
+    match copy.iter().position(|&b| b != flash.erased_val()) {
+        Some(pos) => {
+            info!("First unerased byte at {:#x}", slot.base_off + pos);
+            false
+        }
+        None => true,
+    }
+}
+
+fn verify_trailer(flashmap: &SimFlashMap, slot: &SlotInfo,
+                  magic: Expect<&[u8]>, image_ok: Expect<u8>,
+                  copy_done: Expect<u8>) -> bool {
+    let offset = slot.trailer_off;
+    let max_align = c::boot_max_align();
+    let mut copy = vec![0u8; c::boot_magic_area_sz() + max_align * 2];
     let mut failed = false;
 
+    let flash = &flashmap[&slot.dev_id];
     flash.read(offset, &mut copy).unwrap();
 
+    // Unset fields read as erased flash.
+    let erased = flash.erased_val();
+    let unset_magic = vec![erased; c::boot_magic_sz()];
+    let magic = match magic {
+        Expect::Any => None,
+        Expect::Unset => Some(&unset_magic[..]),
+        Expect::Value(v) => Some(v),
+    };
+    let image_ok = image_ok.resolve(erased);
+    let copy_done = copy_done.resolve(erased);
+
     failed |= match magic {
         Some(v) => {
-            if &copy[16..] != v  {
+            if &copy[copy.len() - c::boot_magic_sz() ..] != v  {
                 warn!("\"magic\" mismatch at {:#x}", offset);
                 true
             } else {
@@ -857,7 +2015,7 @@ fn verify_trailer(flash: &Flash, offset: usize,
 
     failed |= match image_ok {
         Some(v) => {
-            if copy[8] != v {
+            if copy[max_align] != v {
                 warn!("\"image_ok\" mismatch at {:#x}", offset);
                 true
             } else {
@@ -882,34 +2040,11 @@ fn verify_trailer(flash: &Flash, offset: usize,
     !failed
 }
 
-/// The image header
-#[repr(C)]
-pub struct ImageHeader {
-    magic: u32,
-    tlv_size: u16,
-    key_id: u8,
-    _pad1: u8,
-    hdr_size: u16,
-    _pad2: u16,
-    img_size: u32,
-    flags: u32,
-    ver: ImageVersion,
-    _pad3: u32,
-}
-
-impl AsRaw for ImageHeader {}
-
-#[repr(C)]
-pub struct ImageVersion {
-    major: u8,
-    minor: u8,
-    revision: u16,
-    build_num: u32,
-}
-
 struct SlotInfo {
     base_off: usize,
     trailer_off: usize,
+    len: usize,
+    dev_id: u8,
 }
 
 struct Images<'a> {
@@ -919,29 +2054,61 @@ struct Images<'a> {
     upgrade: Vec<u8>,
 }
 
-const MAGIC_VALID: Option<&[u8]> = Some(&[0x77, 0xc2, 0x95, 0xf3,
-                                          0x60, 0xd2, 0xef, 0x7f,
-                                          0x35, 0x52, 0x50, 0x0f,
-                                          0x2c, 0xb6, 0x79, 0x80]);
-const MAGIC_UNSET: Option<&[u8]> = Some(&[0xff; 16]);
+/// What a field of the image trailer is expected to hold.
+#[derive(Clone, Copy)]
+enum Expect<T> {
+    /// Anything; the field isn't checked.
+    Any,
+    /// Nothing has been written, so the field reads as erased flash.
+    Unset,
+    Value(T),
+}
+
+impl Expect<u8> {
+    /// The value to check for, on a flash device that erases to `erased`.
+    fn resolve(self, erased: u8) -> Option<u8> {
+        match self {
+            Expect::Any => None,
+            Expect::Unset => Some(erased),
+            Expect::Value(v) => Some(v),
+        }
+    }
+}
+
+const BOOT_MAGIC: &[u8] = &[0x77, 0xc2, 0x95, 0xf3,
+                            0x60, 0xd2, 0xef, 0x7f,
+                            0x35, 0x52, 0x50, 0x0f,
+                            0x2c, 0xb6, 0x79, 0x80];
+
+const MAGIC_VALID: Expect<&[u8]> = Expect::Value(BOOT_MAGIC);
+const MAGIC_UNSET: Expect<&[u8]> = Expect::Unset;
+const ANY_MAGIC: Expect<&[u8]> = Expect::Any;
 
-const COPY_DONE: Option<u8> = Some(1);
-const IMAGE_OK: Option<u8> = Some(1);
-const UNSET: Option<u8> = Some(0xff);
+const COPY_DONE: Expect<u8> = Expect::Value(1);
+const IMAGE_OK: Expect<u8> = Expect::Value(1);
+const UNSET: Expect<u8> = Expect::Unset;
+const ANY: Expect<u8> = Expect::Any;
 
 /// Write out the magic so that the loader tries doing an upgrade.
-fn mark_upgrade(flash: &mut Flash, slot: &SlotInfo) {
-    let offset = slot.trailer_off + c::boot_max_align() * 2;
-    flash.write(offset, MAGIC_VALID.unwrap()).unwrap();
+fn mark_upgrade(flashmap: &mut SimFlashMap, slot: &SlotInfo) {
+    let flash = flashmap.get_mut(&slot.dev_id).unwrap();
+
+    // Pad the magic at the front to the write size, as the bootloader does.
+    let mut buf = vec![flash.erased_val(); flash.align().saturating_sub(BOOT_MAGIC.len())];
+    buf.extend_from_slice(BOOT_MAGIC);
+
+    let end = slot.trailer_off + c::boot_max_align() * 2 + c::boot_magic_area_sz();
+    flash.write(end - buf.len(), &buf).unwrap();
 }
 
 /// Writes the image_ok flag which, guess what, tells the bootloader
 /// the this image is ok (not a test, and no revert is to be performed).
-fn mark_permanent_upgrade(flash: &mut Flash, slot: &SlotInfo) {
-    let ok = [1u8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
-    let align = c::get_sim_flash_align() as usize;
+fn mark_permanent_upgrade(flashmap: &mut SimFlashMap, slot: &SlotInfo) {
     let off = slot.trailer_off + c::boot_max_align();
-    flash.write(off, &ok[..align]).unwrap();
+    let flash = flashmap.get_mut(&slot.dev_id).unwrap();
+    let mut ok = vec![flash.erased_val(); flash.align()];
+    ok[0] = 1;
+    flash.write(off, &ok).unwrap();
 }
 
 // Drop some pseudo-random gibberish onto the data.
@@ -951,21 +2118,9 @@ fn splat(data: &mut [u8], seed: usize) {
     rng.fill_bytes(data);
 }
 
-/// Return a read-only view into the raw bytes of this object
-trait AsRaw : Sized {
-    fn as_raw<'a>(&'a self) -> &'a [u8] {
-        unsafe { slice::from_raw_parts(self as *const _ as *const u8,
-                                       mem::size_of::<Self>()) }
-    }
-}
-
 fn show_sizes() {
-    // This isn't panic safe.
-    let old_align = c::get_sim_flash_align();
-    for min in &[1, 2, 4, 8] {
-        c::set_sim_flash_align(*min);
-        let msize = c::boot_trailer_sz();
+    for min in all_aligns() {
+        let msize = c::boot_trailer_sz(min);
         println!("{:2}: {} (0x{:x})", min, msize, msize);
     }
-    c::set_sim_flash_align(old_align);
 }
@@ -8,11 +8,18 @@
 //! Because of this header, we have to make two passes.  The first pass will compute the size of
 //! the TLV, and the second pass will build the data for the TLV.
 
-use std::sync::Arc;
-use pem;
+use keys::SigningKey;
 use ring::{digest, rand, signature};
+use std::sync::Arc;
 use untrusted;
 
+#[cfg(feature = "sig-ecdsa-p224")]
+use openssl::ec::EcKey;
+#[cfg(feature = "sig-ecdsa-p224")]
+use openssl::ecdsa::EcdsaSig;
+#[cfg(feature = "sig-ecdsa-p224")]
+use openssl::pkey::Private;
+
 bitflags! {
     struct Flags: u32 {
         const FLAG_PIC = 0x000001;
@@ -40,6 +47,13 @@ pub struct TlvGen {
     kinds: Vec<TlvKinds>,
     size: u16,
     payload: Vec<u8>,
+    // RSA signatures use PSS padding when set, otherwise PKCS#1 v1.5.  This is kept separate from
+    // the flags so that mismatched images can be generated.
+    rsa_pss: bool,
+    // The key to sign with.  If not set, the sample key matching the signature type is used.
+    key: Option<Arc<SigningKey>>,
+    // The key_id to place in the image header.
+    key_id: u8,
 }
 
 impl TlvGen {
@@ -51,6 +65,9 @@ impl TlvGen {
             kinds: vec![TlvKinds::SHA256],
             size: 4 + 32,
             payload: vec![],
+            rsa_pss: true,
+            key: None,
+            key_id: 0,
         }
     }
 
@@ -61,6 +78,63 @@ impl TlvGen {
             kinds: vec![TlvKinds::SHA256, TlvKinds::RSA2048],
             size: 4 + 32 + 4 + 256,
             payload: vec![],
+            rsa_pss: true,
+            key: None,
+            key_id: 0,
+        }
+    }
+
+    /// Construct a new tlv generator that signs with the older PKCS#1 v1.5 RSA padding.
+    #[allow(dead_code)]
+    pub fn new_rsa_pkcs15() -> TlvGen {
+        TlvGen {
+            flags: FLAG_SHA256 | FLAG_PKCS15_RSA2048_SHA256,
+            kinds: vec![TlvKinds::SHA256, TlvKinds::RSA2048],
+            size: 4 + 32 + 4 + 256,
+            payload: vec![],
+            rsa_pss: false,
+            key: None,
+            key_id: 0,
+        }
+    }
+
+    /// Construct a tlv generator whose header flags claim PKCS#1 v1.5, but whose signature is
+    /// actually made with PSS padding.  The bootloader must reject these images.
+    #[allow(dead_code)]
+    pub fn new_rsa_pkcs15_with_pss_sig() -> TlvGen {
+        TlvGen {
+            rsa_pss: true,
+            .. TlvGen::new_rsa_pkcs15()
+        }
+    }
+
+    /// Construct a new tlv generator that signs with ECDSA over the NIST P-256 curve.  The DER
+    /// encoded signature varies in length, and is padded out to the largest size.
+    #[allow(dead_code)]
+    pub fn new_ecdsa_p256() -> TlvGen {
+        TlvGen {
+            flags: FLAG_SHA256 | FLAG_ECDSA256_SHA256,
+            kinds: vec![TlvKinds::SHA256, TlvKinds::ECDSA256],
+            size: 4 + 32 + 4 + 72,
+            payload: vec![],
+            rsa_pss: true,
+            key: None,
+            key_id: 0,
+        }
+    }
+
+    /// Construct a new tlv generator that signs with ECDSA over the NIST P-224 curve.  As with
+    /// P-256, the signature is padded out to the largest size.
+    #[cfg(feature = "sig-ecdsa-p224")]
+    pub fn new_ecdsa_p224() -> TlvGen {
+        TlvGen {
+            flags: FLAG_SHA256 | FLAG_ECDSA224_SHA256,
+            kinds: vec![TlvKinds::SHA256, TlvKinds::ECDSA224],
+            size: 4 + 32 + 4 + 64,
+            payload: vec![],
+            rsa_pss: true,
+            key: None,
+            key_id: 0,
         }
     }
 
@@ -74,6 +148,22 @@ impl TlvGen {
         self.size
     }
 
+    /// Sign with the given key instead of the sample key.  The key must match the signature type.
+    pub fn set_key(&mut self, key: Arc<SigningKey>) {
+        self.key = Some(key);
+    }
+
+    /// Set the key_id placed in the header.  This normally matches the position of the signing key
+    /// in the bootloader's key table, but need not, to test mismatches.
+    pub fn set_key_id(&mut self, key_id: u8) {
+        self.key_id = key_id;
+    }
+
+    /// Retrieve the key_id for the image header.  This can be called at any time.
+    pub fn get_key_id(&self) -> u8 {
+        self.key_id
+    }
+
     /// Add bytes to the covered hash.
     pub fn add_bytes(&mut self, bytes: &[u8]) {
         self.payload.extend_from_slice(bytes);
@@ -96,16 +186,19 @@ impl TlvGen {
         }
 
         if self.kinds.contains(&TlvKinds::RSA2048) {
-            // For now assume PSS.
-            let key_bytes = pem::parse(include_bytes!("../../root-rsa-2048.pem").as_ref()).unwrap();
-            assert_eq!(key_bytes.tag, "RSA PRIVATE KEY");
-            let key_bytes = untrusted::Input::from(&key_bytes.contents);
-            let key = signature::RSAKeyPair::from_der(key_bytes).unwrap();
-            let mut signer = signature::RSASigningState::new(Arc::new(key)).unwrap();
+            let key = self.signing_key(include_bytes!("../../root-rsa-2048.pem"));
+            let key = match *key {
+                SigningKey::Rsa(ref key) => key,
+                _ => panic!("RSA signature requires an RSA key"),
+            };
             let rng = rand::SystemRandom::new();
-            let mut signature = vec![0; signer.key_pair().public_modulus_len()];
+            let mut signature = vec![0; key.public_modulus_len()];
             assert_eq!(signature.len(), 256);
-            signer.sign(&signature::RSA_PSS_SHA256, &rng, &self.payload, &mut signature).unwrap();
+            if self.rsa_pss {
+                key.sign(&signature::RSA_PSS_SHA256, &rng, &self.payload, &mut signature).unwrap();
+            } else {
+                key.sign(&signature::RSA_PKCS1_SHA256, &rng, &self.payload, &mut signature).unwrap();
+            }
 
             result.push(TlvKinds::RSA2048 as u8);
             result.push(0);
@@ -114,6 +207,79 @@ impl TlvGen {
             result.extend_from_slice(&signature);
         }
 
+        if self.kinds.contains(&TlvKinds::ECDSA256) {
+            // ring can only load EC keys in PKCS#8 form.  This is the same key as
+            // root-ec-p256.pem, just wrapped differently.
+            let key = self.signing_key(include_bytes!("../../root-ec-p256-pkcs8.pem"));
+            let key = match *key {
+                SigningKey::EcdsaP256(ref key) => key,
+                _ => panic!("ECDSA P-256 signature requires a P-256 key"),
+            };
+            let rng = rand::SystemRandom::new();
+            let payload = untrusted::Input::from(&self.payload);
+            let signature = key.sign(&rng, payload).unwrap();
+            let mut signature = signature.as_ref().to_vec();
+
+            // The header has already committed to the largest signature size.  Pad with zeros
+            // past the end of the DER sequence, the same as imgtool does.
+            assert!(signature.len() <= 72);
+            signature.resize(72, 0);
+
+            result.push(TlvKinds::ECDSA256 as u8);
+            result.push(0);
+            result.push((signature.len() & 0xFF) as u8);
+            result.push(((signature.len() >> 8) & 0xFF) as u8);
+            result.extend_from_slice(&signature);
+        }
+
+        if self.kinds.contains(&TlvKinds::ECDSA224) {
+            let signature = self.sign_ecdsa_p224();
+
+            result.push(TlvKinds::ECDSA224 as u8);
+            result.push(0);
+            result.push((signature.len() & 0xFF) as u8);
+            result.push(((signature.len() >> 8) & 0xFF) as u8);
+            result.extend_from_slice(&signature);
+        }
+
         result
     }
+
+    /// Return the key to sign with, falling back to the given PEM encoded sample key.
+    fn signing_key(&self, sample: &[u8]) -> Arc<SigningKey> {
+        match self.key {
+            Some(ref key) => key.clone(),
+            None => Arc::new(SigningKey::from_pem(sample).unwrap()),
+        }
+    }
+
+    /// Sign the payload with a P-224 key, padding the DER signature out to 64 bytes.  The
+    /// bootloader strips trailing zeros before decoding it, so a signature that happens to end in
+    /// a zero byte would be truncated.  ECDSA signatures are randomized, so just sign again in
+    /// that case.
+    #[cfg(feature = "sig-ecdsa-p224")]
+    fn sign_ecdsa_p224(&self) -> Vec<u8> {
+        let key = self.signing_key(include_bytes!("../keys/root-ec-p224.pem"));
+        let key: &EcKey<Private> = match *key {
+            SigningKey::EcdsaP224(ref key) => key,
+            _ => panic!("ECDSA P-224 signature requires a P-224 key"),
+        };
+        let hash = digest::digest(&digest::SHA256, &self.payload);
+        let mut signature;
+        loop {
+            signature = EcdsaSig::sign(hash.as_ref(), key).unwrap().to_der().unwrap();
+            if signature.last() != Some(&0) {
+                break;
+            }
+        }
+
+        assert!(signature.len() <= 64);
+        signature.resize(64, 0);
+        signature
+    }
+
+    #[cfg(not(feature = "sig-ecdsa-p224"))]
+    fn sign_ecdsa_p224(&self) -> Vec<u8> {
+        panic!("P-224 signatures require the sig-ecdsa-p224 feature");
+    }
 }